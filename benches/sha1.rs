@@ -0,0 +1,37 @@
+//! Compares the throughput of the available SHA-1 backends on piece-sized buffers.
+//!
+//! Run with `cargo bench --bench sha1`.
+
+use pollinators::verify::{HashBackend, Sha1};
+use std::time::{Duration, Instant};
+
+const PIECE_SIZES: [usize; 3] = [256 * 1024, 4 * 1024 * 1024, 16 * 1024 * 1024];
+const MIN_DURATION: Duration = Duration::from_secs(2);
+
+/// Hashes `data` repeatedly for at least `MIN_DURATION`, returning the throughput in MiB/s.
+fn throughput(backend: HashBackend, data: &[u8]) -> f64 {
+    let start = Instant::now();
+    let mut bytes = 0u64;
+    while start.elapsed() < MIN_DURATION {
+        let mut sha1 = Sha1::with_backend(backend);
+        sha1.update(data);
+        std::hint::black_box(sha1.digest());
+        bytes += data.len() as u64;
+    }
+    bytes as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    println!("detected backend: {:?}", HashBackend::detect());
+    for size in PIECE_SIZES {
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        for backend in [HashBackend::Portable, HashBackend::Accelerated] {
+            println!(
+                "{:>6} KiB pieces, {:<12} {:>8.1} MiB/s",
+                size / 1024,
+                format!("{backend:?}:"),
+                throughput(backend, &data)
+            );
+        }
+    }
+}
@@ -1,63 +1,126 @@
-use anyhow::{anyhow, Result};
+use crate::config::ClientConfig;
+use crate::error::{Error, Result};
+use crate::util::Output;
 use console::style;
 use std::io::Write;
 use std::path::Path;
 use std::process::Command;
 
 pub trait Client {
-    fn add_torrent(&self, torrent_path: &Path, seed_path: &Path) -> Result<()>;
+    /// Adds the torrent at `torrent_path`, seeding from `seed_path`. If `recheck` is false, the
+    /// data is assumed to be complete; otherwise, the client hash checks it before seeding. `tag`
+    /// names the torrent's tracker, for clients that support tagging torrents.
+    fn add_torrent(
+        &self,
+        torrent_path: &Path,
+        seed_path: &Path,
+        recheck: bool,
+        tag: Option<&str>,
+    ) -> Result<()>;
 }
 
-struct Synapse;
+struct Synapse {
+    config: ClientConfig,
+    output: Output,
+}
 
 impl Client for Synapse {
-    fn add_torrent(&self, torrent_path: &Path, seed_path: &Path) -> Result<()> {
-        let output = Command::new("sycli")
-            .arg("add")
-            .arg("--import")
+    // Synapse has no notion of tags or labels, so `tag` is unused.
+    fn add_torrent(
+        &self,
+        torrent_path: &Path,
+        seed_path: &Path,
+        recheck: bool,
+        _tag: Option<&str>,
+    ) -> Result<()> {
+        let mut command = Command::new(
+            self.config
+                .command
+                .as_deref()
+                .unwrap_or_else(|| Path::new("sycli")),
+        );
+        if let Some(server) = &self.config.server {
+            command.arg("--server").arg(server);
+        }
+        // sycli only takes the password as an argument, which exposes it to other local users
+        // for the lifetime of the process. See `ClientConfig::password`.
+        if let Some(password) = &self.config.password {
+            command.arg("--password").arg(password);
+        }
+        command.arg("add");
+        if !recheck {
+            command.arg("--import");
+        }
+        let output = command
             .arg("--directory")
             .arg(seed_path)
             .arg(torrent_path)
-            .output()?;
+            .output()
+            .map_err(Error::ClientSpawn)?;
 
         let result = match output.status.code() {
             Some(0) => Ok(()),
-            Some(code) => Err(anyhow!("client command exited with code {}", code)),
-            None => Err(anyhow!("client terminated by signal")),
+            Some(code) => Err(Error::ClientRejected(code)),
+            None => Err(Error::ClientSignal),
         };
         if result.is_err() {
-            println!(
+            crate::status!(
+                self.output,
                 "failed to add {} from {}",
                 torrent_path.display(),
                 seed_path.display()
             );
-            std::io::stdout().write_all(&output.stdout).unwrap();
-            std::io::stderr().write_all(&output.stderr).unwrap();
+            self.output.write_all(&output.stdout).unwrap();
+            if self.output != Output::Quiet {
+                std::io::stderr().write_all(&output.stderr).unwrap();
+            }
         }
         result
     }
 }
 
-struct DryRun;
+struct DryRun {
+    output: Output,
+}
 
 impl Client for DryRun {
-    fn add_torrent(&self, torrent_path: &Path, seed_path: &Path) -> Result<()> {
-        println!(
-            "{} {} {} {}",
+    fn add_torrent(
+        &self,
+        torrent_path: &Path,
+        seed_path: &Path,
+        recheck: bool,
+        tag: Option<&str>,
+    ) -> Result<()> {
+        crate::status!(
+            self.output,
+            "{} {} {} {}{}{}",
             style("seeding").green(),
             style(torrent_path.display()).cyan(),
             style("from").green(),
-            style(seed_path.display()).cyan()
+            style(seed_path.display()).cyan(),
+            if recheck {
+                style(" after recheck").green()
+            } else {
+                style("")
+            },
+            tag.map_or(style(String::new()), |tag| style(format!(" tagged {tag}"))
+                .green())
         );
         Ok(())
     }
 }
 
+/// Creates the client for `config`, or one that only prints what it would add to `output` for dry
+/// runs. Failures to add a torrent, along with the client's own output, are also printed to
+/// `output`.
 // TODO: Support more clients.
-pub fn new_instance(dry_run: bool) -> Box<dyn Client> {
+pub fn new_instance(dry_run: bool, config: &ClientConfig, output: Output) -> Box<dyn Client> {
     if dry_run {
-        Box::new(DryRun {})
+        Box::new(DryRun { output })
     } else {
-        Box::new(Synapse {})
+        Box::new(Synapse {
+            config: config.clone(),
+            output,
+        })
     }
 }
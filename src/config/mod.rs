@@ -0,0 +1,567 @@
+use crate::cross_seed::Layout;
+use crate::fs::{LinkType, OnConflict};
+use crate::util::Output;
+use crate::verify::{
+    ConcurrencyLimits, IoPriority, RateLimits, ReadMode, RetryPolicy, VerifyMode, VerifyOptions,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Connection details for the torrent client.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Path to the client command; defaults to `sycli` from `PATH`.
+    pub command: Option<PathBuf>,
+    pub server: Option<String>,
+    /// Passed to the client command as `--password`, since `sycli` has no other way to take it.
+    /// Command arguments are visible to other local users, e.g. in `ps` or `/proc/<pid>/cmdline`;
+    /// on shared machines, leave this unset and configure the password in `sycli` itself.
+    pub password: Option<String>,
+}
+
+/// A registered tracker, keyed in the config by a canonical short name that is used for
+/// directory naming, client tags and reports. A torrent belongs to the tracker if its announce
+/// hostname is the key itself or matches one of `hosts`, or failing that, if its `source` tag is
+/// one of `sources`. Also holds overrides applied to the tracker's torrents.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TrackerConfig {
+    /// Announce hostnames used by the tracker. A leading `*.` matches any subdomain, e.g. for
+    /// per-user announce domains.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Values of the `source` field in the info dictionary that identify the tracker.
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// Whether the tracker is private. Torrents without the private flag are never cross-seeded
+    /// into a private tracker's directory.
+    pub private: Option<bool>,
+    /// Directory name under the target dir; defaults to the tracker's name.
+    pub directory: Option<String>,
+    /// Overrides the profile's layout for this tracker.
+    pub layout: Option<Layout>,
+    /// Substituted for `{category}` in the layout.
+    pub category: Option<String>,
+    /// Overrides the profile's `pieces_to_test` for this tracker.
+    pub pieces_to_test: Option<usize>,
+    pub sample_coverage: Option<f64>,
+    pub verify: Option<VerifyMode>,
+    pub link_type: Option<LinkType>,
+    pub skip_add: Option<bool>,
+}
+
+/// A set of options. The top level of the config file is itself a profile; named profiles are
+/// layered on top of it. Unknown keys are rejected, so a misspelled option is not silently
+/// ignored.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub source_dirs: Vec<PathBuf>,
+    pub target_dir: Option<PathBuf>,
+    /// How many pieces to check per file on average in a sampled check. The total budget, this
+    /// times the number of files, is spread across files in proportion to their size. Each
+    /// file's first and last piece are always checked and count towards it. Defaults to 3.
+    pub pieces_to_test: Option<usize>,
+    /// Percentage of pieces to check in a sampled check; overrides `pieces_to_test` if set.
+    pub sample_coverage: Option<f64>,
+    /// Seed for choosing pieces in a sampled check.
+    pub seed: Option<u64>,
+    /// How to check matches. Defaults to `none` with `trust_client`, `sample` for dry runs or
+    /// when skipping adding to the client, and `full` otherwise.
+    pub verify: Option<VerifyMode>,
+    /// Have the client hash check torrents when adding them, instead of importing them as
+    /// complete.
+    pub trust_client: Option<bool>,
+    pub link_type: Option<LinkType>,
+    /// Where to create links under the target dir, e.g. `{tracker}/{name}-{infohash8}`. Defaults
+    /// to `{tracker}`.
+    pub layout: Option<Layout>,
+    /// Substituted for `{category}` in the layout, unless the tracker sets its own.
+    pub category: Option<String>,
+    /// What to do when a link's destination already exists. Defaults to `fail`.
+    pub on_conflict: Option<OnConflict>,
+    pub skip_add: Option<bool>,
+    pub read_mode: Option<ReadMode>,
+    /// How many times to retry reads of source files that fail with transient errors.
+    pub read_retries: Option<u32>,
+    pub read_retry_delay_ms: Option<u64>,
+    /// How many workers may read concurrently from a spinning disk.
+    pub hdd_concurrency: Option<usize>,
+    /// How many workers may read concurrently from other devices; unlimited by default.
+    pub ssd_concurrency: Option<usize>,
+    /// Per-device concurrency overrides, keyed by any path on the device.
+    #[serde(default)]
+    pub device_concurrency: HashMap<PathBuf, usize>,
+    /// Limit on how fast source files are read across all devices, in bytes per second.
+    pub max_read_rate: Option<u64>,
+    /// Limit on how fast source files are read from each device, in bytes per second.
+    pub max_device_read_rate: Option<u64>,
+    /// Per-device read rate overrides, keyed by any path on the device.
+    #[serde(default)]
+    pub device_read_rate: HashMap<PathBuf, u64>,
+    pub io_priority: Option<IoPriority>,
+    /// Where to save progress of full hash checks, so they can be resumed if interrupted.
+    /// Defaults to `$XDG_STATE_HOME/pollinators/checkpoints`.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Set to false to disable checkpointing.
+    pub checkpoint: Option<bool>,
+    /// Where to record created links and directories, so a run can be undone. Defaults to
+    /// `$XDG_STATE_HOME/pollinators/journal.jsonl`.
+    pub journal: Option<PathBuf>,
+    /// How many torrents to verify and cross-seed concurrently. Defaults to 1.
+    pub jobs: Option<usize>,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub trackers: HashMap<String, TrackerConfig>,
+}
+
+impl Profile {
+    /// Layers `other` on top of `self`: any value set in `other` takes precedence.
+    pub fn merge(mut self, other: Profile) -> Profile {
+        if !other.source_dirs.is_empty() {
+            self.source_dirs = other.source_dirs;
+        }
+        self.target_dir = other.target_dir.or(self.target_dir);
+        self.pieces_to_test = other.pieces_to_test.or(self.pieces_to_test);
+        self.sample_coverage = other.sample_coverage.or(self.sample_coverage);
+        self.seed = other.seed.or(self.seed);
+        self.verify = other.verify.or(self.verify);
+        self.trust_client = other.trust_client.or(self.trust_client);
+        self.link_type = other.link_type.or(self.link_type);
+        self.on_conflict = other.on_conflict.or(self.on_conflict);
+        self.layout = other.layout.or(self.layout);
+        self.category = other.category.or(self.category);
+        self.skip_add = other.skip_add.or(self.skip_add);
+        self.read_mode = other.read_mode.or(self.read_mode);
+        self.read_retries = other.read_retries.or(self.read_retries);
+        self.read_retry_delay_ms = other.read_retry_delay_ms.or(self.read_retry_delay_ms);
+        self.hdd_concurrency = other.hdd_concurrency.or(self.hdd_concurrency);
+        self.ssd_concurrency = other.ssd_concurrency.or(self.ssd_concurrency);
+        self.device_concurrency.extend(other.device_concurrency);
+        self.max_read_rate = other.max_read_rate.or(self.max_read_rate);
+        self.max_device_read_rate = other.max_device_read_rate.or(self.max_device_read_rate);
+        self.device_read_rate.extend(other.device_read_rate);
+        self.io_priority = other.io_priority.or(self.io_priority);
+        self.checkpoint_dir = other.checkpoint_dir.or(self.checkpoint_dir);
+        self.checkpoint = other.checkpoint.or(self.checkpoint);
+        self.journal = other.journal.or(self.journal);
+        self.jobs = other.jobs.or(self.jobs);
+        self.client = ClientConfig {
+            command: other.client.command.or(self.client.command),
+            server: other.client.server.or(self.client.server),
+            password: other.client.password.or(self.client.password),
+        };
+        self.trackers.extend(other.trackers);
+        self
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(try_from = "toml::Table")]
+pub struct Config {
+    pub defaults: Profile,
+    pub profiles: HashMap<String, Profile>,
+}
+
+// Not derived with `#[serde(flatten)]`, which would let the top-level profile ignore unknown keys.
+impl TryFrom<toml::Table> for Config {
+    type Error = toml::de::Error;
+
+    fn try_from(mut table: toml::Table) -> Result<Config, toml::de::Error> {
+        let profiles = match table.remove("profiles") {
+            Some(profiles) => profiles.try_into()?,
+            None => HashMap::new(),
+        };
+        Ok(Config {
+            defaults: toml::Value::Table(table).try_into()?,
+            profiles,
+        })
+    }
+}
+
+impl Config {
+    /// Loads the config at `path`, or from the default location if `path` is `None`. A missing
+    /// config file at the default location is not an error.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => {
+                return Ok(Config::default())
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("reading config {}", path.display()))
+            }
+        };
+        toml::from_str(&contents).with_context(|| format!("parsing config {}", path.display()))
+    }
+
+    /// Returns the top-level options with the named profile, if any, layered on top.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile> {
+        match name {
+            None => Ok(self.defaults.clone()),
+            Some(name) => {
+                let profile = self
+                    .profiles
+                    .get(name)
+                    .ok_or_else(|| anyhow!("no profile named {name} in config"))?;
+                Ok(self.defaults.clone().merge(profile.clone()))
+            }
+        }
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("pollinators").join("config.toml"))
+}
+
+/// Where checkpoints, the journal and other persistent state are kept:
+/// `$XDG_STATE_HOME/pollinators`.
+pub fn state_dir() -> Option<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(state_home.join("pollinators"))
+}
+
+/// Fully-resolved options for a run.
+#[derive(Debug)]
+pub struct Settings {
+    pub source_dirs: Vec<PathBuf>,
+    pub target_dir: PathBuf,
+    pub dry_run: bool,
+    pub skip_add: bool,
+    pub pieces_to_test: usize,
+    /// Fraction of pieces to check in a sampled check.
+    pub sample_coverage: Option<f64>,
+    pub seed: Option<u64>,
+    pub verify_mode: Option<VerifyMode>,
+    pub trust_client: bool,
+    pub link_type: LinkType,
+    pub layout: Layout,
+    pub category: Option<String>,
+    pub on_conflict: OnConflict,
+    pub verify: VerifyOptions,
+    /// Where to checkpoint full hash checks, or `None` if disabled.
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Where to record filesystem changes, or `None` for dry runs.
+    pub journal: Option<PathBuf>,
+    pub jobs: usize,
+    pub client: ClientConfig,
+    pub trackers: HashMap<String, TrackerConfig>,
+    /// Where to print status messages and progress. Quiet unless set by the caller.
+    pub output: Output,
+}
+
+/// Options for a single torrent, after applying any per-tracker overrides.
+#[derive(Debug)]
+pub struct TrackerSettings<'a> {
+    /// The canonical name of the registered tracker, if any.
+    pub name: Option<&'a str>,
+    pub private: bool,
+    pub directory: Option<&'a str>,
+    pub layout: &'a Layout,
+    pub category: Option<&'a str>,
+    pub skip_add: bool,
+    pub pieces_to_test: usize,
+    pub sample_coverage: Option<f64>,
+    pub verify_mode: VerifyMode,
+    pub link_type: LinkType,
+}
+
+impl Settings {
+    pub fn new(profile: Profile, dry_run: bool) -> Result<Settings> {
+        Ok(Settings {
+            source_dirs: profile.source_dirs,
+            target_dir: profile.target_dir.ok_or_else(|| {
+                anyhow!("target dir must be set on the command line or in config")
+            })?,
+            dry_run,
+            skip_add: profile.skip_add.unwrap_or(false),
+            pieces_to_test: profile.pieces_to_test.unwrap_or(3),
+            sample_coverage: profile.sample_coverage.map(|percent| percent / 100.0),
+            seed: profile.seed,
+            verify_mode: profile.verify,
+            trust_client: profile.trust_client.unwrap_or(false),
+            link_type: profile.link_type.unwrap_or_default(),
+            layout: profile.layout.unwrap_or_default(),
+            category: profile.category,
+            on_conflict: profile.on_conflict.unwrap_or_default(),
+            verify: VerifyOptions {
+                read_mode: profile.read_mode.unwrap_or_default(),
+                retry: RetryPolicy {
+                    retries: profile
+                        .read_retries
+                        .unwrap_or(RetryPolicy::default().retries),
+                    delay: profile
+                        .read_retry_delay_ms
+                        .map_or(RetryPolicy::default().delay, Duration::from_millis),
+                },
+                concurrency: ConcurrencyLimits {
+                    rotational: profile
+                        .hdd_concurrency
+                        .unwrap_or(ConcurrencyLimits::default().rotational),
+                    non_rotational: profile.ssd_concurrency,
+                    devices: profile.device_concurrency,
+                },
+                rate_limits: RateLimits {
+                    global: profile.max_read_rate,
+                    per_device: profile.max_device_read_rate,
+                    devices: profile.device_read_rate,
+                },
+                io_priority: profile.io_priority.unwrap_or_default(),
+                limiter: None,
+                output: Output::default(),
+            },
+            // Dry runs do not write anything, including checkpoints.
+            checkpoint_dir: if dry_run || profile.checkpoint == Some(false) {
+                None
+            } else {
+                profile
+                    .checkpoint_dir
+                    .or_else(crate::verify::default_checkpoint_dir)
+            },
+            journal: if dry_run {
+                None
+            } else {
+                profile.journal.or_else(crate::journal::default_path)
+            },
+            jobs: profile.jobs.unwrap_or(1),
+            client: profile.client,
+            trackers: profile.trackers,
+            output: Output::default(),
+        })
+    }
+
+    /// Finds the registered tracker for a torrent with the given announce hostname and `source`
+    /// tag. Hostnames are matched first, then the source tag; ties go to the first name in sorted
+    /// order.
+    pub fn find_tracker(
+        &self,
+        host: Option<&str>,
+        source: Option<&str>,
+    ) -> Option<(&str, &TrackerConfig)> {
+        let mut trackers: Vec<_> = self.trackers.iter().collect();
+        trackers.sort_by_key(|(name, _)| *name);
+        let by_host = host.and_then(|host| {
+            self.trackers.get_key_value(host).or_else(|| {
+                trackers.iter().copied().find(|(_, tracker)| {
+                    tracker
+                        .hosts
+                        .iter()
+                        .any(|pattern| host_matches(pattern, host))
+                })
+            })
+        });
+        let by_source = || {
+            let source = source?;
+            trackers.iter().copied().find(|(_, tracker)| {
+                tracker
+                    .sources
+                    .iter()
+                    .any(|tag| tag.eq_ignore_ascii_case(source))
+            })
+        };
+        by_host
+            .or_else(by_source)
+            .map(|(name, tracker)| (name.as_str(), tracker))
+    }
+
+    pub fn for_tracker(&self, host: Option<&str>, source: Option<&str>) -> TrackerSettings<'_> {
+        let (name, tracker) = self.find_tracker(host, source).unzip();
+        let skip_add = tracker.and_then(|t| t.skip_add).unwrap_or(self.skip_add);
+        let verify_mode = tracker
+            .and_then(|t| t.verify)
+            .or(self.verify_mode)
+            .unwrap_or(if self.trust_client {
+                VerifyMode::None
+            } else if self.dry_run || skip_add {
+                VerifyMode::Sample
+            } else {
+                VerifyMode::Full
+            });
+        TrackerSettings {
+            name,
+            private: tracker.and_then(|t| t.private).unwrap_or(false),
+            directory: tracker.and_then(|t| t.directory.as_deref()),
+            layout: tracker
+                .and_then(|t| t.layout.as_ref())
+                .unwrap_or(&self.layout),
+            category: tracker
+                .and_then(|t| t.category.as_deref())
+                .or(self.category.as_deref()),
+            skip_add,
+            pieces_to_test: tracker
+                .and_then(|t| t.pieces_to_test)
+                .unwrap_or(self.pieces_to_test),
+            sample_coverage: tracker
+                .and_then(|t| t.sample_coverage.map(|percent| percent / 100.0))
+                .or(self.sample_coverage),
+            verify_mode,
+            link_type: tracker.and_then(|t| t.link_type).unwrap_or(self.link_type),
+        }
+    }
+}
+
+/// Whether `host` matches `pattern`, which is either a hostname or `*.` followed by a domain.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let (pattern, host) = (pattern.to_ascii_lowercase(), host.to_ascii_lowercase());
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        source_dirs = ["/data/a"]
+        target_dir = "/data/links"
+
+        [client]
+        server = "ws://localhost:8412"
+
+        [trackers."tracker.example.org"]
+        directory = "example"
+        link_type = "hardlink"
+        layout = "{tracker}/{category}/{name}-{infohash8}"
+        category = "movies"
+
+        [trackers.two]
+        private = true
+        hosts = ["two.example.net", "*.two.example.org"]
+        sources = ["TWO"]
+
+        [profiles.hdd]
+        source_dirs = ["/hdd/a", "/hdd/b"]
+        pieces_to_test = 1
+
+        [profiles.hdd.client]
+        password = "hunter2"
+    "#;
+
+    #[test]
+    fn profile_layers_on_defaults() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let profile = config.profile(Some("hdd")).unwrap();
+        assert_eq!(
+            profile.source_dirs,
+            vec![PathBuf::from("/hdd/a"), PathBuf::from("/hdd/b")]
+        );
+        assert_eq!(profile.target_dir, Some(PathBuf::from("/data/links")));
+        assert_eq!(profile.pieces_to_test, Some(1));
+        assert_eq!(
+            profile.client.server.as_deref(),
+            Some("ws://localhost:8412")
+        );
+        assert_eq!(profile.client.password.as_deref(), Some("hunter2"));
+        assert!(config.profile(Some("ssd")).is_err());
+    }
+
+    #[test]
+    fn tracker_overrides() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let settings = Settings::new(config.profile(None).unwrap(), false).unwrap();
+        let tracker = settings.for_tracker(Some("tracker.example.org"), None);
+        assert_eq!(tracker.directory, Some("example"));
+        assert_eq!(tracker.link_type, LinkType::Hardlink);
+        assert_eq!(tracker.pieces_to_test, 3);
+        assert_eq!(
+            *tracker.layout,
+            "{tracker}/{category}/{name}-{infohash8}".parse().unwrap()
+        );
+        assert_eq!(tracker.category, Some("movies"));
+
+        let tracker = settings.for_tracker(Some("other.example.org"), None);
+        assert_eq!(tracker.directory, None);
+        assert_eq!(tracker.link_type, LinkType::Symlink);
+        assert_eq!(*tracker.layout, Layout::default());
+        assert_eq!(tracker.name, None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        for config in [
+            "target_dirs = \"/data/links\"",
+            "[trackers.two]\nskip-add = true",
+            "[profiles.hdd]\npieces_to_tset = 1",
+            "[client]\npasword = \"hunter2\"",
+        ] {
+            assert!(toml::from_str::<Config>(config).is_err(), "{config}");
+        }
+    }
+
+    #[test]
+    fn tracker_registry() {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let settings = Settings::new(config.profile(None).unwrap(), false).unwrap();
+        let name = |host, source| settings.for_tracker(host, source).name;
+        assert_eq!(
+            name(Some("tracker.example.org"), None),
+            Some("tracker.example.org")
+        );
+        assert_eq!(name(Some("two.example.net"), None), Some("two"));
+        assert!(settings.for_tracker(Some("two.example.net"), None).private);
+        assert_eq!(name(Some("u1234.two.example.org"), None), Some("two"));
+        assert_eq!(name(Some("two.example.org"), None), None);
+        assert_eq!(name(Some("xtwo.example.org"), None), None);
+        assert_eq!(name(None, Some("two")), Some("two"));
+        assert_eq!(
+            name(Some("tracker.example.org"), Some("TWO")),
+            Some("tracker.example.org")
+        );
+    }
+
+    #[test]
+    fn default_verify_mode() {
+        let profile = |skip_add, trust_client| Profile {
+            target_dir: Some(PathBuf::from("/data/links")),
+            skip_add: Some(skip_add),
+            trust_client: Some(trust_client),
+            ..Default::default()
+        };
+        let mode = |profile, dry_run| {
+            Settings::new(profile, dry_run)
+                .unwrap()
+                .for_tracker(None, None)
+                .verify_mode
+        };
+        assert_eq!(mode(profile(false, false), false), VerifyMode::Full);
+        assert_eq!(mode(profile(false, false), true), VerifyMode::Sample);
+        assert_eq!(mode(profile(true, false), false), VerifyMode::Sample);
+        assert_eq!(mode(profile(false, true), false), VerifyMode::None);
+        assert_eq!(
+            mode(
+                Profile {
+                    verify: Some(VerifyMode::Full),
+                    ..profile(false, true)
+                },
+                true
+            ),
+            VerifyMode::Full
+        );
+    }
+}
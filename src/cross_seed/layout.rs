@@ -0,0 +1,191 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A value that can be substituted into a layout.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Placeholder {
+    /// The tracker's configured directory or registered name, or else the announce hostname or
+    /// `source` tag.
+    Tracker,
+    /// The announce hostname.
+    Host,
+    /// The tracker's configured category.
+    Category,
+    /// The torrent's name.
+    Name,
+    /// The full hex-encoded info-hash.
+    InfoHash,
+    /// The first 8 characters of the hex-encoded info-hash.
+    InfoHash8,
+    /// The `source` field of the torrent's info dictionary, which some private trackers set.
+    Source,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Placeholder> {
+        Some(match name {
+            "tracker" => Placeholder::Tracker,
+            "host" => Placeholder::Host,
+            "category" => Placeholder::Category,
+            "name" => Placeholder::Name,
+            "infohash" => Placeholder::InfoHash,
+            "infohash8" => Placeholder::InfoHash8,
+            "source" => Placeholder::Source,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A template for the directory links are created in, relative to the target dir, e.g.
+/// `{tracker}/{category}/{name}-{infohash8}`. Each `/`-separated component may mix literal text
+/// with placeholders. Components that expand to nothing, e.g. an unset `{category}`, are dropped.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Layout {
+    components: Vec<Vec<Segment>>,
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout {
+            components: vec![vec![Segment::Placeholder(Placeholder::Tracker)]],
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Layout, String> {
+        if template.starts_with('/') {
+            return Err(format!(
+                "layout {template} must be relative to the target dir"
+            ));
+        }
+        let mut components = vec![];
+        for component in template.split('/').filter(|c| !c.is_empty()) {
+            let mut segments = vec![];
+            let mut rest = component;
+            while let Some(start) = rest.find('{') {
+                if start > 0 {
+                    segments.push(Segment::Literal(rest[..start].to_owned()));
+                }
+                let end = rest[start..]
+                    .find('}')
+                    .ok_or_else(|| format!("unterminated placeholder in layout {template}"))?;
+                let name = &rest[start + 1..start + end];
+                segments.push(Segment::Placeholder(
+                    Placeholder::from_name(name).ok_or_else(|| {
+                        format!("unknown placeholder {{{name}}} in layout {template}")
+                    })?,
+                ));
+                rest = &rest[start + end + 1..];
+            }
+            if rest.contains('}') {
+                return Err(format!("unmatched }} in layout {template}"));
+            }
+            if !rest.is_empty() {
+                segments.push(Segment::Literal(rest.to_owned()));
+            }
+            if segments == [Segment::Literal(".".into())]
+                || segments == [Segment::Literal("..".into())]
+            {
+                return Err(format!(
+                    "layout {template} may not contain . or .. components"
+                ));
+            }
+            components.push(segments);
+        }
+        Ok(Layout { components })
+    }
+}
+
+impl TryFrom<String> for Layout {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Layout, String> {
+        template.parse()
+    }
+}
+
+impl Layout {
+    /// Expands the layout into a relative path, looking up each placeholder with `value`. Values
+    /// are sanitized so they cannot introduce extra components or escape the target dir.
+    pub fn render<E>(
+        &self,
+        mut value: impl FnMut(Placeholder) -> Result<Option<String>, E>,
+    ) -> Result<PathBuf, E> {
+        let mut path = PathBuf::new();
+        for component in &self.components {
+            let mut expanded = String::new();
+            for segment in component {
+                match segment {
+                    Segment::Literal(literal) => expanded.push_str(literal),
+                    Segment::Placeholder(placeholder) => {
+                        if let Some(value) = value(*placeholder)? {
+                            expanded.push_str(&sanitize(&value));
+                        }
+                    }
+                }
+            }
+            match expanded.as_str() {
+                "" => {}
+                "." | ".." => path.push("_"),
+                _ => path.push(expanded),
+            }
+        }
+        Ok(path)
+    }
+}
+
+fn sanitize(value: &str) -> String {
+    value.replace(['/', '\0'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(layout: &Layout) -> PathBuf {
+        layout
+            .render(|placeholder| {
+                Ok::<_, ()>(match placeholder {
+                    Placeholder::Tracker => Some("tracker.example.org".into()),
+                    Placeholder::Name => Some("Some/Release".into()),
+                    Placeholder::InfoHash8 => Some("0123abcd".into()),
+                    Placeholder::Source => Some("..".into()),
+                    _ => None,
+                })
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(
+            render(&Layout::default()),
+            PathBuf::from("tracker.example.org")
+        );
+        let layout: Layout = "{tracker}/{category}/{name}-{infohash8}".parse().unwrap();
+        assert_eq!(
+            render(&layout),
+            PathBuf::from("tracker.example.org/Some_Release-0123abcd")
+        );
+        let layout: Layout = "links/{source}".parse().unwrap();
+        assert_eq!(render(&layout), PathBuf::from("links/_"));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        for template in ["/abs/{name}", "{name", "{nmae}", "name}", "{tracker}/../x"] {
+            assert!(template.parse::<Layout>().is_err(), "{template}");
+        }
+    }
+}
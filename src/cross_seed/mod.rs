@@ -0,0 +1,796 @@
+use crate::client;
+use crate::config;
+use crate::error::{self, Error};
+use crate::fs;
+use crate::journal::{self, Journal};
+use crate::matching::{self, PathHelper};
+use crate::report;
+use crate::status;
+use crate::torrent;
+use crate::util::Output;
+use crate::verify;
+use console::style;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+mod layout;
+
+pub use layout::{Layout, Placeholder};
+
+pub trait CrossSeed {
+    fn announce_host(&self) -> Option<String>;
+    fn base_dir(
+        &self,
+        target_dir: &Path,
+        tracker: &config::TrackerSettings,
+    ) -> error::Result<PathBuf>;
+    fn cross_seed(
+        &self,
+        path: &Path,
+        settings: &config::Settings,
+        tracker: &config::TrackerSettings,
+        candidates: &HashMap<&Path, &Path>,
+        journal: Option<&Journal>,
+        report: &mut report::TorrentReport,
+    ) -> error::Result<()>;
+}
+
+impl CrossSeed for torrent::Torrent {
+    /// The host of the first tracker with a usable URL.
+    fn announce_host(&self) -> Option<String> {
+        self.trackers().find_map(|announce| {
+            url::Url::parse(announce)
+                .ok()?
+                .host_str()
+                .map(str::to_owned)
+        })
+    }
+
+    fn base_dir(
+        &self,
+        target_dir: &Path,
+        tracker: &config::TrackerSettings,
+    ) -> error::Result<PathBuf> {
+        // Falls back to the `source` tag for torrents without a usable tracker URL.
+        let tracker_name = || -> error::Result<String> {
+            if let Some(host) = self.announce_host().or_else(|| self.info.source.clone()) {
+                return Ok(host);
+            }
+            let announce = self.trackers().next().ok_or(Error::NoTracker)?;
+            Err(match url::Url::parse(announce) {
+                Ok(_) => Error::NoHostname(torrent::redact_url(announce)),
+                Err(source) => Error::InvalidAnnounce {
+                    announce: torrent::redact_url(announce),
+                    source,
+                },
+            })
+        };
+        let info_hash = self.info_hash.to_string();
+        let relative = tracker.layout.render(|placeholder| {
+            Ok::<_, Error>(match placeholder {
+                Placeholder::Tracker => match tracker.directory.or(tracker.name) {
+                    Some(directory) => Some(directory.to_owned()),
+                    None => Some(tracker_name()?),
+                },
+                Placeholder::Host => self.announce_host(),
+                Placeholder::Category => tracker.category.map(str::to_owned),
+                Placeholder::Name => Some(self.info.name.clone()),
+                Placeholder::InfoHash => Some(info_hash.clone()),
+                Placeholder::InfoHash8 => Some(info_hash[..8].to_owned()),
+                Placeholder::Source => self.info.source.clone(),
+            })
+        })?;
+        Ok(target_dir.join(relative))
+    }
+
+    fn cross_seed(
+        &self,
+        path: &Path,
+        settings: &config::Settings,
+        tracker: &config::TrackerSettings,
+        candidates: &HashMap<&Path, &Path>,
+        journal: Option<&Journal>,
+        report: &mut report::TorrentReport,
+    ) -> error::Result<()> {
+        let dry_run = settings.dry_run;
+        let output = settings.output;
+        let tag = report.tracker.clone();
+        let add_torrent = |seed_path: &Path| {
+            if tracker.skip_add {
+                return Ok(());
+            }
+            client::new_instance(dry_run, &settings.client, output).add_torrent(
+                path,
+                seed_path,
+                settings.trust_client,
+                tag.as_deref(),
+            )
+        };
+        let record = |action| -> error::Result<()> {
+            journal.map_or(Ok(()), |journal| {
+                journal
+                    .record(path, &self.info_hash, action)
+                    .map_err(Error::Journal)
+            })
+        };
+        let create_dir_all = |dir: &Path| -> error::Result<()> {
+            // Only directories that did not already exist are recorded, outermost first.
+            let missing: Vec<_> = dir
+                .ancestors()
+                .take_while(|ancestor| !ancestor.as_os_str().is_empty() && !ancestor.exists())
+                .collect();
+            fs::new_instance(dry_run, output)
+                .create_dir_all(dir)
+                .map_err(|source| Error::CreateDir {
+                    path: dir.to_path_buf(),
+                    source,
+                })?;
+            for created in missing.into_iter().rev() {
+                record(journal::Action::CreateDir {
+                    path: created.to_path_buf(),
+                })?;
+            }
+            Ok(())
+        };
+        // Returns whether a new link was created, or false if an identical one already existed or
+        // a conflicting entry was skipped.
+        let mut link = |original: &Path, link: PathBuf| {
+            let fs = fs::new_instance(dry_run, output);
+            if fs.is_linked(tracker.link_type, original, &link) {
+                return Ok(false);
+            }
+            if std::fs::symlink_metadata(&link).is_ok()
+                && !resolve_conflict(&*fs, settings.on_conflict, &link, record, report, output)?
+            {
+                return Ok(false);
+            }
+            if let Err(source) = fs.link(tracker.link_type, original, &link) {
+                return Err(Error::Link {
+                    link,
+                    original: original.to_path_buf(),
+                    source,
+                });
+            }
+            record(journal::Action::Link {
+                link: link.clone(),
+                original: original.to_path_buf(),
+                link_type: tracker.link_type,
+            })?;
+            report.links_created.push(report::LinkRecord {
+                link,
+                original: original.to_path_buf(),
+                link_type: tracker.link_type,
+            });
+            Ok(true)
+        };
+        let linked_status = |created| {
+            if created {
+                report::Status::Linked
+            } else {
+                report::Status::AlreadyPresent
+            }
+        };
+
+        if self.info.is_single_file {
+            let (source, target) = candidates.iter().next().unwrap();
+            let seed_path = if *source == target.file_name().unwrap() {
+                let seed_path = target.parent().unwrap().to_path_buf();
+                status!(
+                    output,
+                    "torrent can be directly seeded from {}",
+                    seed_path.display()
+                );
+                report.status = report::Status::SeededDirectly;
+                seed_path
+            } else {
+                let base_dir = self.base_dir(&settings.target_dir, tracker)?;
+                status!(
+                    output,
+                    "{} {}",
+                    style("found matches with different filenames; creating symlinks in").blue(),
+                    base_dir.display()
+                );
+                create_dir_all(&base_dir)?;
+                report.status = linked_status(link(target, base_dir.join(source))?);
+                base_dir
+            };
+            report.seed_path = Some(seed_path.clone());
+            return add_torrent(&seed_path);
+        }
+
+        // Check if symlinks are needed at all; if the same prefix can be used for all selected
+        // candidate paths, then a symlink is sufficient.
+        let path_prefix: HashSet<Option<PathBuf>> = candidates
+            .iter()
+            .map(|(source, target)| target.remove_common_suffix(source))
+            .collect();
+        if !path_prefix.contains(&None) && path_prefix.len() == 1 {
+            let seed_path = path_prefix.into_iter().next().unwrap().unwrap();
+            status!(
+                output,
+                "torrent can be directly seeded from {}",
+                seed_path.display()
+            );
+            report.status = report::Status::SeededDirectly;
+            report.seed_path = Some(seed_path.clone());
+            return add_torrent(&seed_path);
+        }
+        let base_dir = self.base_dir(&settings.target_dir, tracker)?;
+        status!(
+            output,
+            "{} {}",
+            style("found matches with different filenames; creating symlinks in").blue(),
+            base_dir.display()
+        );
+        let root = base_dir.join(&self.info.name);
+        let existing = std::fs::symlink_metadata(&root).ok();
+        let created = if existing.as_ref().is_some_and(std::fs::Metadata::is_dir) {
+            // An earlier run already moved a tree into place; fill in anything missing.
+            let mut created = false;
+            for (source_path, target_path) in candidates {
+                if let Some(parent) = source_path.parent() {
+                    create_dir_all(&base_dir.join(parent))?;
+                }
+                created |= link(target_path, base_dir.join(source_path))?;
+            }
+            created
+        } else if existing.is_some()
+            && !resolve_conflict(
+                &*fs::new_instance(dry_run, output),
+                settings.on_conflict,
+                &root,
+                record,
+                report,
+                output,
+            )?
+        {
+            false
+        } else {
+            create_dir_all(&base_dir)?;
+            let mut links: Vec<_> = candidates
+                .iter()
+                .map(|(source_path, target_path)| (base_dir.join(source_path), *target_path))
+                .collect();
+            links.sort();
+            if dry_run {
+                // Nothing is staged in a dry run, so print the links as they would end up.
+                let fs = fs::new_instance(dry_run, output);
+                let mut created_dir = None;
+                for (link, original) in &links {
+                    if let Some(parent) =
+                        link.parent().filter(|parent| created_dir != Some(*parent))
+                    {
+                        created_dir = Some(parent);
+                        fs.create_dir_all(parent)
+                            .map_err(|source| Error::CreateDir {
+                                path: parent.to_path_buf(),
+                                source,
+                            })?;
+                    }
+                    fs.link(tracker.link_type, original, link)
+                        .map_err(|source| Error::Link {
+                            link: link.clone(),
+                            original: original.to_path_buf(),
+                            source,
+                        })?;
+                }
+            } else {
+                stage_links(
+                    &*fs::new_instance(dry_run, output),
+                    &root,
+                    &links,
+                    tracker.link_type,
+                    output,
+                )?;
+            }
+            let mut dirs: Vec<_> = links
+                .iter()
+                .flat_map(|(link, _)| link.ancestors().skip(1))
+                .filter(|dir| dir.starts_with(&root))
+                .collect();
+            dirs.sort();
+            dirs.dedup();
+            for dir in dirs {
+                record(journal::Action::CreateDir {
+                    path: dir.to_path_buf(),
+                })?;
+            }
+            for (link, original) in links {
+                record(journal::Action::Link {
+                    link: link.clone(),
+                    original: original.to_path_buf(),
+                    link_type: tracker.link_type,
+                })?;
+                report.links_created.push(report::LinkRecord {
+                    link,
+                    original: original.to_path_buf(),
+                    link_type: tracker.link_type,
+                });
+            }
+            true
+        };
+        report.status = linked_status(created);
+        report.seed_path = Some(base_dir.clone());
+        add_torrent(&base_dir)
+    }
+}
+
+/// Handles an existing entry at `path` that is in the way of a link, according to `on_conflict`.
+/// Returns whether the link should still be created.
+fn resolve_conflict(
+    fs: &dyn fs::Filesystem,
+    on_conflict: fs::OnConflict,
+    path: &Path,
+    record: impl Fn(journal::Action) -> error::Result<()>,
+    report: &mut report::TorrentReport,
+    output: Output,
+) -> error::Result<bool> {
+    let renamed_to = match on_conflict {
+        fs::OnConflict::Fail => return Err(Error::Conflict(path.to_path_buf())),
+        fs::OnConflict::Skip => {
+            status!(
+                output,
+                "{} {}",
+                style("skipping existing").yellow(),
+                path.display()
+            );
+            None
+        }
+        fs::OnConflict::Replace => {
+            fs.remove_file(path)
+                .map_err(|source| Error::ResolveConflict {
+                    path: path.to_path_buf(),
+                    source,
+                })?;
+            record(journal::Action::Remove {
+                path: path.to_path_buf(),
+            })?;
+            None
+        }
+        fs::OnConflict::Rename => {
+            let aside = unused_name(path);
+            fs.rename(path, &aside)
+                .map_err(|source| Error::ResolveConflict {
+                    path: path.to_path_buf(),
+                    source,
+                })?;
+            record(journal::Action::Rename {
+                from: path.to_path_buf(),
+                to: aside.clone(),
+            })?;
+            Some(aside)
+        }
+    };
+    report.conflicts.push(report::ConflictRecord {
+        path: path.to_path_buf(),
+        resolution: on_conflict,
+        renamed_to,
+    });
+    Ok(on_conflict != fs::OnConflict::Skip)
+}
+
+/// The first of `<path>.conflict`, `<path>.conflict.1`, ... that does not exist yet.
+fn unused_name(path: &Path) -> PathBuf {
+    let mut base = path.as_os_str().to_owned();
+    base.push(".conflict");
+    std::iter::once(PathBuf::from(&base))
+        .chain((1..).map(|n| {
+            let mut name = base.clone();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        }))
+        .find(|candidate| std::fs::symlink_metadata(candidate).is_err())
+        .unwrap()
+}
+
+/// Creates `links`, which all live under `root`, in a staging directory next to `root` and renames
+/// it into place only once every link exists, so a failure never leaves a half-populated tree
+/// behind. The staging directory is removed on failure.
+fn stage_links(
+    fs: &dyn fs::Filesystem,
+    root: &Path,
+    links: &[(PathBuf, &Path)],
+    link_type: fs::LinkType,
+    output: Output,
+) -> error::Result<()> {
+    // Concurrent workers may be staging torrents with the same name.
+    static NEXT_STAGING: AtomicUsize = AtomicUsize::new(0);
+    let staging = root.with_file_name(format!(
+        ".{}.staging-{}-{}",
+        root.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        NEXT_STAGING.fetch_add(1, Ordering::Relaxed)
+    ));
+    let result = links.iter().try_for_each(|(link, original)| {
+        // Torrent paths are validated when parsed, but a link must never escape the staging
+        // directory regardless.
+        let relative = link
+            .strip_prefix(root)
+            .ok()
+            .filter(|relative| {
+                relative
+                    .components()
+                    .all(|component| matches!(component, std::path::Component::Normal(_)))
+            })
+            .ok_or_else(|| Error::UnsafePath(link.clone()))?;
+        let link = staging.join(relative);
+        if let Some(parent) = link.parent() {
+            fs.create_dir_all(parent)
+                .map_err(|source| Error::CreateDir {
+                    path: parent.to_path_buf(),
+                    source,
+                })?;
+        }
+        fs.link(link_type, original, &link)
+            .map_err(|source| Error::Link {
+                link,
+                original: original.to_path_buf(),
+                source,
+            })
+    });
+    let result = result.and_then(|()| {
+        fs.rename(&staging, root).map_err(|source| Error::Stage {
+            staging: staging.clone(),
+            path: root.to_path_buf(),
+            source,
+        })
+    });
+    if result.is_err() {
+        match fs.remove_dir_all(&staging) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                status!(
+                    output,
+                    "unable to remove staging directory {}: {err}",
+                    staging.display()
+                );
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// A torrent that has been parsed and matched, and is ready to be verified and cross-seeded.
+struct Prepared<'a> {
+    path: &'a Path,
+    torrent: &'a torrent::Torrent,
+    tracker: config::TrackerSettings<'a>,
+    candidates: HashMap<&'a Path, &'a Path>,
+    /// The pieces to check, or `None` if verification is disabled.
+    selection: Option<verify::Selection<'a>>,
+    checkpoint: Option<verify::Checkpoint>,
+}
+
+pub fn load_torrent(path: &Path) -> error::Result<torrent::Torrent> {
+    let bytes = std::fs::read(path).map_err(|source| Error::ReadTorrent {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Ok(torrent::Torrent::from_bytes(&bytes)?)
+}
+
+/// Finds source files for `torrent` and chooses which pieces to check.
+fn prepare<'a>(
+    path: &'a Path,
+    torrent: &'a torrent::Torrent,
+    settings: &'a config::Settings,
+    entries: &'a HashMap<u64, Vec<PathBuf>>,
+    report: &mut report::TorrentReport,
+) -> error::Result<Prepared<'a>> {
+    report.info_hash = Some(torrent.info_hash.to_string());
+    report.name = Some(torrent.info.name.clone());
+    let host = torrent.announce_host();
+    let tracker = settings.for_tracker(host.as_deref(), torrent.info.source.as_deref());
+    report.tracker = tracker.name.map(str::to_owned).or(host);
+    if tracker.private && !torrent.info.private {
+        return Err(Error::PublicTorrent(
+            tracker.name.unwrap_or_default().to_owned(),
+        ));
+    }
+    let candidates = matching::find_candidates(&torrent.info, entries)?;
+    report.matched_files = candidates
+        .iter()
+        .map(|(source, target)| (source.to_path_buf(), target.to_path_buf()))
+        .collect();
+    let (selection, checkpoint) = match tracker.verify_mode {
+        verify::VerifyMode::None => (None, None),
+        mode => {
+            let full = mode == verify::VerifyMode::Full;
+            let sample = verify::SampleOptions {
+                pieces_per_file: tracker.pieces_to_test,
+                coverage: tracker.sample_coverage,
+                seed: settings.seed,
+            };
+            let selection = verify::select_pieces(&torrent.info.pieces, full, &sample);
+            let checkpoint = settings
+                .checkpoint_dir
+                .as_deref()
+                .filter(|_| full)
+                .and_then(|dir| {
+                    verify::Checkpoint::load(dir, &torrent.info_hash, &torrent.info, &candidates)
+                });
+            (Some(selection), checkpoint)
+        }
+    };
+    Ok(Prepared {
+        path,
+        torrent,
+        tracker,
+        candidates,
+        selection,
+        checkpoint,
+    })
+}
+
+/// Verifies and cross-seeds a prepared torrent with `options`, reusing any results in `cache`.
+fn finish(
+    prepared: &Prepared,
+    settings: &config::Settings,
+    options: &verify::VerifyOptions,
+    cache: Option<&verify::PieceCache>,
+    journal: Option<&Journal>,
+    report: &mut report::TorrentReport,
+) -> error::Result<()> {
+    status!(settings.output, "processing {}", prepared.path.display());
+    if let Some(selection) = &prepared.selection {
+        let verification = verify::verify(
+            &prepared.torrent.info,
+            selection,
+            &prepared.candidates,
+            prepared.checkpoint.as_ref(),
+            cache,
+            options,
+        );
+        report.hash_check = Some(verification.stats.clone());
+        report.file_checks = verification.files.clone();
+        verification.into_result()?;
+    }
+
+    prepared.torrent.cross_seed(
+        prepared.path,
+        settings,
+        &prepared.tracker,
+        &prepared.candidates,
+        journal,
+        report,
+    )
+}
+
+/// Matches, verifies and cross-seeds the torrent at `path`, recording the outcome in `report`.
+pub fn process_torrent(
+    path: &Path,
+    settings: &config::Settings,
+    entries: &HashMap<u64, Vec<PathBuf>>,
+    report: &mut report::TorrentReport,
+) -> error::Result<()> {
+    let torrent = load_torrent(path)?;
+    let prepared = prepare(path, &torrent, settings, entries, report)?;
+    let journal = open_journal(settings).map_err(Error::Journal)?;
+    let options = verify::VerifyOptions {
+        output: settings.output,
+        ..settings.verify.clone()
+    };
+    finish(
+        &prepared,
+        settings,
+        &options,
+        None,
+        journal.as_ref(),
+        report,
+    )
+}
+
+fn open_journal(settings: &config::Settings) -> std::io::Result<Option<Journal>> {
+    settings.journal.as_deref().map(Journal::open).transpose()
+}
+
+/// Processes every torrent in `paths`, passing each report to `on_report` in order. All torrents
+/// are matched up front, so that pieces covering the same source data in more than one torrent
+/// can be checked once and the result shared. Up to `settings.jobs` torrents are then verified and
+/// cross-seeded concurrently. Every link and directory created is recorded in the journal.
+pub fn process_batch(
+    paths: &[PathBuf],
+    settings: &config::Settings,
+    entries: &HashMap<u64, Vec<PathBuf>>,
+    mut on_report: impl FnMut(report::TorrentReport) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let journal = open_journal(settings)?;
+    let mut reports: Vec<_> = paths
+        .iter()
+        .map(|path| report::TorrentReport::new(path))
+        .collect();
+    let torrents: Vec<_> = paths
+        .iter()
+        .zip(&mut reports)
+        .map(|(path, report)| {
+            load_torrent(path)
+                .map_err(|err| report.record_error(&err, settings.output))
+                .ok()
+        })
+        .collect();
+    let prepared: Vec<_> = paths
+        .iter()
+        .zip(&torrents)
+        .zip(&mut reports)
+        .map(|((path, torrent), report)| {
+            prepare(path, torrent.as_ref()?, settings, entries, report)
+                .map_err(|err| report.record_error(&err, settings.output))
+                .ok()
+        })
+        .collect();
+
+    // Every worker reads through the same limiter, so running several jobs does not multiply the
+    // configured read rates or device concurrency.
+    let options = verify::VerifyOptions {
+        limiter: Some(Arc::new(verify::Limiter::new(&settings.verify))),
+        output: settings.output,
+        ..settings.verify.clone()
+    };
+    let mut cache = verify::PieceCache::default();
+    if paths.len() > 1 {
+        let checks: Vec<_> = prepared
+            .iter()
+            .flatten()
+            .filter_map(|prepared| {
+                Some(verify::SharedCheck {
+                    selection: prepared.selection.as_ref()?,
+                    mapping: &prepared.candidates,
+                    checkpoint: prepared.checkpoint.as_ref(),
+                })
+            })
+            .collect();
+        cache = verify::verify_shared(&checks, &options);
+    }
+
+    // Torrents are verified and linked by a pool of workers, but reported in their original
+    // order.
+    let slots: Vec<_> = prepared
+        .into_iter()
+        .zip(reports)
+        .map(|item| Mutex::new(Some(item)))
+        .collect();
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    std::thread::scope(|scope| {
+        for _ in 0..settings.jobs.clamp(1, slots.len().max(1)) {
+            let sender = sender.clone();
+            let (slots, next, options, cache, journal) =
+                (&slots, &next, &options, &cache, journal.as_ref());
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(slot) = slots.get(index) else {
+                    break;
+                };
+                let (prepared, mut report) = slot.lock().unwrap().take().unwrap();
+                if let Some(prepared) = prepared {
+                    if let Err(err) = finish(
+                        &prepared,
+                        settings,
+                        options,
+                        Some(cache),
+                        journal,
+                        &mut report,
+                    ) {
+                        report.record_error(&err, settings.output);
+                    }
+                }
+                if sender.send((index, report)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+        let mut pending = BTreeMap::new();
+        let mut next_report = 0;
+        for (index, report) in receiver {
+            pending.insert(index, report);
+            while let Some(report) = pending.remove(&next_report) {
+                on_report(report)?;
+                next_report += 1;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    })?;
+    if let Some(journal) = journal.filter(|journal| !journal.is_empty()) {
+        status!(
+            settings.output,
+            "changes recorded; to revert them, run: pollinators undo --run {}",
+            journal.run()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stage_links_all_or_nothing() {
+        let dir = crate::util::TempDir::new("stage");
+        let original = dir.join("original");
+        std::fs::write(&original, b"data").unwrap();
+        let missing = dir.join("missing");
+        let root = dir.join("Release");
+        let fs = fs::new_instance(false, Output::Quiet);
+
+        // Hard linking a missing original fails after the first link has been created.
+        let links = [
+            (root.join("a.mkv"), original.as_path()),
+            (root.join("Subs/b.srt"), missing.as_path()),
+        ];
+        assert!(stage_links(&*fs, &root, &links, fs::LinkType::Hardlink, Output::Quiet).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let links = [
+            (root.join("a.mkv"), original.as_path()),
+            (root.join("Subs/b.srt"), original.as_path()),
+        ];
+        stage_links(&*fs, &root, &links, fs::LinkType::Hardlink, Output::Quiet).unwrap();
+        assert!(root.join("a.mkv").is_file());
+        assert!(root.join("Subs/b.srt").is_file());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Links that would escape the root are refused before anything is created.
+        let root = dir.join("Other");
+        for escaping in [dir.join("x.mkv"), root.join("../x.mkv")] {
+            let links = [(escaping, original.as_path())];
+            assert!(matches!(
+                stage_links(&*fs, &root, &links, fs::LinkType::Hardlink, Output::Quiet),
+                Err(Error::UnsafePath(_))
+            ));
+        }
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn conflicts_renamed_aside() {
+        let dir = crate::util::TempDir::new("conflict");
+        let path = dir.join("a.mkv");
+        std::fs::write(&path, b"existing").unwrap();
+        std::fs::write(dir.join("a.mkv.conflict"), b"older").unwrap();
+        assert_eq!(unused_name(&path), dir.join("a.mkv.conflict.1"));
+
+        let fs = fs::new_instance(false, Output::Quiet);
+        let mut report = report::TorrentReport::default();
+        let record = |_| Ok(());
+        assert!(matches!(
+            resolve_conflict(
+                &*fs,
+                fs::OnConflict::Fail,
+                &path,
+                record,
+                &mut report,
+                Output::Quiet
+            ),
+            Err(Error::Conflict(_))
+        ));
+        assert!(!resolve_conflict(
+            &*fs,
+            fs::OnConflict::Skip,
+            &path,
+            record,
+            &mut report,
+            Output::Quiet
+        )
+        .unwrap());
+        assert!(path.exists());
+        assert!(resolve_conflict(
+            &*fs,
+            fs::OnConflict::Rename,
+            &path,
+            record,
+            &mut report,
+            Output::Quiet
+        )
+        .unwrap());
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read(dir.join("a.mkv.conflict.1")).unwrap(),
+            b"existing"
+        );
+        assert_eq!(report.conflicts.len(), 2);
+    }
+}
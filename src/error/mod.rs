@@ -0,0 +1,147 @@
+use crate::report::FileCheck;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Errors from processing a single torrent, grouped by the stage that produced them.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unable to read torrent {path}: {source}")]
+    ReadTorrent {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("unable to parse torrent: {0}")]
+    ParseTorrent(#[from] serde_bencode::Error),
+
+    #[error("unable to find candidate matches for file {path} with size {length}")]
+    NoSizeMatch { path: PathBuf, length: u64 },
+
+    #[error("no mapping for {0}")]
+    NoMapping(PathBuf),
+    #[error("I/O error reading {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("pread failed for {path}: read {actual} bytes at offset {offset} instead of {expected} bytes")]
+    ShortRead {
+        path: PathBuf,
+        offset: u64,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("unable to read source files: {failures:#?}")]
+    ReadFailures { failures: BTreeMap<PathBuf, String> },
+    #[error("hash check failed for {} files:{}", files.len(), format_file_checks(files))]
+    HashMismatch { files: Vec<FileCheck> },
+
+    /// The announce URL is redacted, since it usually contains a passkey.
+    #[error("invalid announce URL {announce}: {source}")]
+    InvalidAnnounce {
+        announce: String,
+        source: url::ParseError,
+    },
+    #[error("announce URL {0} has no hostname")]
+    NoHostname(String),
+    #[error("torrent has no trackers or source tag to name its directory after")]
+    NoTracker,
+    #[error("refusing to cross-seed a public torrent for private tracker {0}")]
+    PublicTorrent(String),
+
+    #[error("unable to create directory {path}: {source}")]
+    CreateDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("unable to link {link} to {original}: {source}")]
+    Link {
+        link: PathBuf,
+        original: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{0} already exists and is not a link to the matched file")]
+    Conflict(PathBuf),
+    #[error("unable to move existing {path} out of the way: {source}")]
+    ResolveConflict {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("refusing to create {0} outside the torrent's directory")]
+    UnsafePath(PathBuf),
+    #[error("unable to move {staging} into place at {path}: {source}")]
+    Stage {
+        staging: PathBuf,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("unable to record change in journal: {0}")]
+    Journal(std::io::Error),
+
+    #[error("unable to run client command: {0}")]
+    ClientSpawn(std::io::Error),
+    #[error("client rejected torrent: command exited with code {0}")]
+    ClientRejected(i32),
+    #[error("client terminated by signal")]
+    ClientSignal,
+}
+
+fn format_file_checks(files: &[FileCheck]) -> String {
+    let mut result = String::new();
+    for file in files {
+        let _ = write!(result, "\n  {file}");
+    }
+    result
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A coarse classification of `Error`, suitable for machine-readable output.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The torrent file could not be read or parsed.
+    Parse,
+    /// Some file in the torrent had no source file with a matching size.
+    NoSizeMatch,
+    /// Reading source files failed.
+    Io,
+    /// Source files were read successfully, but their hashes did not match.
+    HashMismatch,
+    /// Creating directories or links failed.
+    Link,
+    /// The torrent was refused by a configured policy.
+    Policy,
+    /// The torrent client could not be run.
+    ClientUnavailable,
+    /// The torrent client refused to add the torrent.
+    ClientRejected,
+}
+
+impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::ReadTorrent { .. } | Error::ParseTorrent(_) => ErrorCategory::Parse,
+            Error::NoSizeMatch { .. } => ErrorCategory::NoSizeMatch,
+            Error::NoMapping(_)
+            | Error::Read { .. }
+            | Error::ShortRead { .. }
+            | Error::ReadFailures { .. } => ErrorCategory::Io,
+            Error::HashMismatch { .. } => ErrorCategory::HashMismatch,
+            Error::PublicTorrent(_) => ErrorCategory::Policy,
+            Error::InvalidAnnounce { .. }
+            | Error::NoHostname(_)
+            | Error::NoTracker
+            | Error::CreateDir { .. }
+            | Error::Link { .. }
+            | Error::Conflict(_)
+            | Error::ResolveConflict { .. }
+            | Error::UnsafePath(_)
+            | Error::Stage { .. }
+            | Error::Journal(_) => ErrorCategory::Link,
+            Error::ClientSpawn(_) => ErrorCategory::ClientUnavailable,
+            Error::ClientRejected(_) | Error::ClientSignal => ErrorCategory::ClientRejected,
+        }
+    }
+}
@@ -1,12 +1,74 @@
+use crate::util::Output;
 use console::Style;
+use serde::{Deserialize, Serialize};
 use std::path::Path;
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkType {
+    #[default]
+    Symlink,
+    Hardlink,
+}
+
+/// What to do when a link's destination already exists but is not the expected link.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Leave the existing entry in place and do not create the link.
+    Skip,
+    /// Remove the existing entry and create the link.
+    Replace,
+    /// Move the existing entry aside to an unused name and create the link.
+    Rename,
+    /// Fail the torrent.
+    #[default]
+    Fail,
+}
+
 pub trait Filesystem {
     fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
         std::fs::create_dir_all(path)
     }
 
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
     fn symlink(&self, original: &Path, link: &Path) -> std::io::Result<()>;
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()>;
+
+    fn link(&self, link_type: LinkType, original: &Path, link: &Path) -> std::io::Result<()> {
+        match link_type {
+            LinkType::Symlink => self.symlink(original, link),
+            LinkType::Hardlink => self.hard_link(original, link),
+        }
+    }
+
+    /// Whether `link` already exists and is a link of the given type to `original`.
+    fn is_linked(&self, link_type: LinkType, original: &Path, link: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        match link_type {
+            LinkType::Symlink => std::fs::read_link(link).is_ok_and(|target| target == original),
+            LinkType::Hardlink => {
+                match (std::fs::metadata(original), std::fs::symlink_metadata(link)) {
+                    (Ok(original), Ok(link)) => {
+                        original.dev() == link.dev() && original.ino() == link.ino()
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
 }
 
 struct PosixFilesystem;
@@ -15,40 +77,88 @@ impl Filesystem for PosixFilesystem {
     fn symlink(&self, original: &Path, link: &Path) -> std::io::Result<()> {
         std::os::unix::fs::symlink(original, link)
     }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        std::fs::hard_link(original, link)
+    }
 }
 
 fn get_default_instance() -> Box<dyn Filesystem> {
     Box::new(PosixFilesystem {})
 }
 
-struct DryRunFilesystem;
+struct DryRunFilesystem {
+    output: Output,
+}
 
 impl Filesystem for DryRunFilesystem {
     fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
         let cyan = Style::new().cyan();
-        println!("creating directories at {}", cyan.apply_to(path.display()));
+        crate::status!(
+            self.output,
+            "creating directories at {}",
+            cyan.apply_to(path.display())
+        );
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        let cyan = Style::new().cyan();
+        crate::status!(
+            self.output,
+            "moving {} into place at {}",
+            cyan.apply_to(from.display()),
+            cyan.apply_to(to.display())
+        );
+        Ok(())
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        let cyan = Style::new().cyan();
+        crate::status!(self.output, "removing {}", cyan.apply_to(path.display()));
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        let cyan = Style::new().cyan();
+        crate::status!(self.output, "removing {}", cyan.apply_to(path.display()));
         Ok(())
     }
 
     fn symlink(&self, original: &Path, link: &Path) -> std::io::Result<()> {
         let cyan = Style::new().cyan();
         let magenta = Style::new().magenta();
-        println!(
+        crate::status!(
+            self.output,
             "symlinking {} to {}",
             cyan.apply_to(link.display()),
             magenta.apply_to(original.display())
         );
         Ok(())
     }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        let cyan = Style::new().cyan();
+        let magenta = Style::new().magenta();
+        crate::status!(
+            self.output,
+            "hard linking {} to {}",
+            cyan.apply_to(link.display()),
+            magenta.apply_to(original.display())
+        );
+        Ok(())
+    }
 }
 
-fn get_dry_run_instance() -> Box<dyn Filesystem> {
-    Box::new(DryRunFilesystem {})
+fn get_dry_run_instance(output: Output) -> Box<dyn Filesystem> {
+    Box::new(DryRunFilesystem { output })
 }
 
-pub fn new_instance(dry_run: bool) -> Box<dyn Filesystem> {
+/// Creates the filesystem to link files with, or for dry runs, one that only prints what it would
+/// do to `output`.
+pub fn new_instance(dry_run: bool, output: Output) -> Box<dyn Filesystem> {
     if dry_run {
-        get_dry_run_instance()
+        get_dry_run_instance(output)
     } else {
         get_default_instance()
     }
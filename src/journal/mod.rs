@@ -0,0 +1,461 @@
+use crate::fs::LinkType;
+use crate::status;
+use crate::torrent;
+use crate::util::Output;
+use console::style;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A filesystem change made while cross-seeding.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Action {
+    CreateDir {
+        path: PathBuf,
+    },
+    Link {
+        link: PathBuf,
+        original: PathBuf,
+        link_type: LinkType,
+    },
+    /// An existing entry was moved aside to make room for a link.
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// An existing entry was removed to make room for a link. This cannot be undone.
+    Remove {
+        path: PathBuf,
+    },
+}
+
+/// A single line in the journal.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Entry {
+    /// Identifies the invocation that made the change.
+    pub run: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub torrent: PathBuf,
+    pub info_hash: String,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// An append-only log of every filesystem change, written as JSON Lines, so that a run can be
+/// undone later.
+pub struct Journal {
+    run: String,
+    file: Mutex<std::fs::File>,
+    recorded: AtomicBool,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, starting a new run.
+    pub fn open(path: &Path) -> std::io::Result<Journal> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Journal {
+            run: format!("{}-{}", now(), std::process::id()),
+            file: Mutex::new(file),
+            recorded: AtomicBool::new(false),
+        })
+    }
+
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+
+    /// Whether any change has been recorded in this run.
+    pub fn is_empty(&self) -> bool {
+        !self.recorded.load(Ordering::Relaxed)
+    }
+
+    pub fn record(
+        &self,
+        torrent: &Path,
+        info_hash: &torrent::Digest,
+        action: Action,
+    ) -> std::io::Result<()> {
+        // Paths are made absolute so the run can be undone from any directory. The original of a
+        // symlink is kept as is, since that is what the symlink points to.
+        let action = match action {
+            Action::CreateDir { path } => Action::CreateDir {
+                path: std::path::absolute(path)?,
+            },
+            Action::Link {
+                link,
+                original,
+                link_type,
+            } => Action::Link {
+                link: std::path::absolute(link)?,
+                original: match link_type {
+                    LinkType::Hardlink => std::path::absolute(original)?,
+                    LinkType::Symlink => original,
+                },
+                link_type,
+            },
+            Action::Rename { from, to } => Action::Rename {
+                from: std::path::absolute(from)?,
+                to: std::path::absolute(to)?,
+            },
+            Action::Remove { path } => Action::Remove {
+                path: std::path::absolute(path)?,
+            },
+        };
+        let entry = Entry {
+            run: self.run.clone(),
+            timestamp: now(),
+            torrent: std::path::absolute(torrent)?,
+            info_hash: info_hash.to_string(),
+            action,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // Written with a single call, so concurrent workers never interleave partial lines.
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.flush()?;
+        self.recorded.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// The default journal: `$XDG_STATE_HOME/pollinators/journal.jsonl`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(crate::config::state_dir()?.join("journal.jsonl"))
+}
+
+/// Reads every entry in the journal at `path`. Malformed lines, e.g. from a crash mid-write, are
+/// skipped.
+pub fn load(path: &Path) -> std::io::Result<Vec<Entry>> {
+    let file = std::fs::File::open(path)?;
+    let mut entries = vec![];
+    for line in std::io::BufReader::new(file).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Which journal entries to undo.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Only undo this run. Defaults to the most recent run, unless `torrent` is set.
+    pub run: Option<String>,
+    /// Only undo changes for the torrent with this info-hash or path.
+    pub torrent: Option<String>,
+}
+
+impl Filter {
+    /// Selects the matching entries, most recent first.
+    pub fn select(&self, entries: &[Entry]) -> Vec<Entry> {
+        let run = match (&self.run, &self.torrent) {
+            (Some(run), _) => Some(run.as_str()),
+            (None, Some(_)) => None,
+            (None, None) => entries.last().map(|entry| entry.run.as_str()),
+        };
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| run.is_none_or(|run| entry.run == run))
+            .filter(|entry| {
+                self.torrent.as_deref().is_none_or(|torrent| {
+                    entry.info_hash.eq_ignore_ascii_case(torrent)
+                        || std::path::absolute(torrent).is_ok_and(|path| entry.torrent == path)
+                })
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Counts of undone changes.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct UndoSummary {
+    /// Changes that were reverted, or would be in a dry run.
+    pub reverted: usize,
+    /// Changes that were already reverted, e.g. by an earlier undo.
+    pub missing: usize,
+    /// Changes left in place because the path was modified after it was created, or that cannot
+    /// be reverted.
+    pub refused: usize,
+}
+
+/// Reverts `entries` in order. Anything that has changed since it was created is left alone: links
+/// that no longer point to their original, hard links whose original is gone, and directories that
+/// are no longer empty. Entries moved aside are moved back if nothing has taken their place. Each
+/// change, and each entry left alone, is printed to `output`.
+pub fn undo(entries: &[Entry], dry_run: bool, output: Output) -> UndoSummary {
+    let mut summary = UndoSummary::default();
+    for entry in entries {
+        let path = match &entry.action {
+            Action::CreateDir { path } => path,
+            Action::Link { link, .. } => link,
+            Action::Rename { from, to } => {
+                undo_rename(from, to, dry_run, &mut summary, output);
+                continue;
+            }
+            Action::Remove { path } => {
+                refuse(
+                    &mut summary,
+                    path,
+                    "it was replaced and cannot be restored",
+                    output,
+                );
+                continue;
+            }
+        };
+        let metadata = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                summary.missing += 1;
+                continue;
+            }
+            Err(err) => {
+                refuse(&mut summary, path, &err.to_string(), output);
+                continue;
+            }
+        };
+        let unchanged = match &entry.action {
+            Action::CreateDir { .. } => metadata
+                .is_dir()
+                .then_some(())
+                .ok_or("no longer a directory"),
+            Action::Link {
+                original,
+                link_type: LinkType::Symlink,
+                ..
+            } => std::fs::read_link(path)
+                .is_ok_and(|target| target == *original)
+                .then_some(())
+                .ok_or("symlink target changed"),
+            Action::Link {
+                original,
+                link_type: LinkType::Hardlink,
+                ..
+            } => std::fs::metadata(original)
+                .is_ok_and(|original| {
+                    original.dev() == metadata.dev() && original.ino() == metadata.ino()
+                })
+                .then_some(())
+                .ok_or("no longer a hard link to the original"),
+            Action::Rename { .. } | Action::Remove { .. } => unreachable!(),
+        };
+        if let Err(reason) = unchanged {
+            refuse(&mut summary, path, reason, output);
+            continue;
+        }
+        if dry_run {
+            status!(output, "would remove {}", style(path.display()).cyan());
+            summary.reverted += 1;
+            continue;
+        }
+        let result = if metadata.is_dir() {
+            // Fails if anything else has been put in the directory since.
+            std::fs::remove_dir(path)
+        } else {
+            std::fs::remove_file(path)
+        };
+        match result {
+            Ok(()) => {
+                status!(output, "removed {}", style(path.display()).cyan());
+                summary.reverted += 1;
+            }
+            Err(err) => refuse(&mut summary, path, &err.to_string(), output),
+        }
+    }
+    summary
+}
+
+/// Moves an entry that was moved aside from `from` to `to` back again.
+fn undo_rename(from: &Path, to: &Path, dry_run: bool, summary: &mut UndoSummary, output: Output) {
+    if std::fs::symlink_metadata(to).is_err() {
+        summary.missing += 1;
+        return;
+    }
+    if std::fs::symlink_metadata(from).is_ok() {
+        refuse(
+            summary,
+            to,
+            &format!("{} has been reused", from.display()),
+            output,
+        );
+        return;
+    }
+    if dry_run {
+        status!(
+            output,
+            "would move {} back to {}",
+            style(to.display()).cyan(),
+            style(from.display()).cyan()
+        );
+        summary.reverted += 1;
+        return;
+    }
+    match std::fs::rename(to, from) {
+        Ok(()) => {
+            status!(
+                output,
+                "moved {} back to {}",
+                style(to.display()).cyan(),
+                style(from.display()).cyan()
+            );
+            summary.reverted += 1;
+        }
+        Err(err) => refuse(summary, to, &err.to_string(), output),
+    }
+}
+
+fn refuse(summary: &mut UndoSummary, path: &Path, reason: &str, output: Output) {
+    status!(
+        output,
+        "{} {}: {}",
+        style("leaving").yellow(),
+        path.display(),
+        reason
+    );
+    summary.refused += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_reverts_unchanged_paths() {
+        let dir = crate::util::TempDir::new("journal");
+        let original = dir.join("original");
+        std::fs::write(&original, b"data").unwrap();
+        let links = dir.join("links");
+        std::fs::create_dir(&links).unwrap();
+        std::os::unix::fs::symlink(&original, links.join("a")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), links.join("b")).unwrap();
+
+        let journal_path = dir.join("journal.jsonl");
+        let journal = Journal::open(&journal_path).unwrap();
+        let hash = torrent::Digest::default();
+        let record = |action| {
+            journal
+                .record(Path::new("t.torrent"), &hash, action)
+                .unwrap()
+        };
+        record(Action::CreateDir {
+            path: links.clone(),
+        });
+        for name in ["a", "b"] {
+            record(Action::Link {
+                link: links.join(name),
+                original: original.clone(),
+                link_type: LinkType::Symlink,
+            });
+        }
+
+        let entries = Filter::default().select(&load(&journal_path).unwrap());
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            undo(&entries, false, Output::Quiet),
+            UndoSummary {
+                reverted: 1,
+                missing: 0,
+                refused: 2,
+            }
+        );
+        assert!(!links.join("a").exists());
+        assert!(links.join("b").is_symlink());
+
+        std::fs::remove_file(links.join("b")).unwrap();
+        assert_eq!(
+            undo(&entries, false, Output::Quiet),
+            UndoSummary {
+                reverted: 1,
+                missing: 2,
+                refused: 0,
+            }
+        );
+        assert!(!links.exists());
+    }
+
+    #[test]
+    fn hard_link_originals_are_absolute() {
+        let dir = crate::util::TempDir::new("journal");
+        let journal_path = dir.join("journal.jsonl");
+        let journal = Journal::open(&journal_path).unwrap();
+        for link_type in [LinkType::Hardlink, LinkType::Symlink] {
+            journal
+                .record(
+                    Path::new("t.torrent"),
+                    &torrent::Digest::default(),
+                    Action::Link {
+                        link: PathBuf::from("link"),
+                        original: PathBuf::from("../original"),
+                        link_type,
+                    },
+                )
+                .unwrap();
+        }
+        let originals: Vec<_> = load(&journal_path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry.action {
+                Action::Link { original, .. } => original,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            originals,
+            [
+                std::path::absolute("../original").unwrap(),
+                PathBuf::from("../original")
+            ]
+        );
+    }
+
+    #[test]
+    fn filter_defaults_to_latest_run() {
+        let entry = |run: &str, info_hash: &str| Entry {
+            run: run.into(),
+            timestamp: 0,
+            torrent: std::path::absolute(format!("{info_hash}.torrent")).unwrap(),
+            info_hash: info_hash.into(),
+            action: Action::CreateDir {
+                path: PathBuf::from(run),
+            },
+        };
+        let entries = [entry("1", "aa"), entry("2", "aa"), entry("2", "bb")];
+        assert_eq!(
+            Filter::default().select(&entries),
+            vec![entries[2].clone(), entries[1].clone()]
+        );
+        let filter = Filter {
+            run: None,
+            torrent: Some("AA".into()),
+        };
+        assert_eq!(
+            filter.select(&entries),
+            vec![entries[1].clone(), entries[0].clone()]
+        );
+        let filter = Filter {
+            run: Some("1".into()),
+            torrent: Some("bb.torrent".into()),
+        };
+        assert_eq!(filter.select(&entries), vec![]);
+    }
+}
@@ -0,0 +1,28 @@
+//! Finds existing data on disk that matches a torrent's contents, verifies it, and prepares it to
+//! be cross-seeded, linking files into place when the on-disk names differ from the torrent's.
+//!
+//! The `pollinators` binary is a thin wrapper around `cross_seed::process_batch`, which processes
+//! many torrents at once (`cross_seed::process_torrent` handles a single one); the individual
+//! stages are also available for callers that want to drive them directly:
+//!
+//! - `torrent`: parsing `.torrent` files.
+//! - `matching`: finding candidate source files for each file in a torrent.
+//! - `verify`: hash checking candidates against the torrent's pieces.
+//! - `cross_seed`: linking matched files into place and adding the torrent to a client.
+//! - `journal`: recording the links and directories `cross_seed` creates, so they can be undone.
+//! - `fs` and `client`: the filesystem and torrent client backends used by `cross_seed`.
+//!
+//! Library functions print nothing and draw no progress bars unless given a `util::Output` to
+//! write to, e.g. through `config::Settings::output`; the binary picks one based on `--output`.
+
+pub mod client;
+pub mod config;
+pub mod cross_seed;
+pub mod error;
+pub mod fs;
+pub mod journal;
+pub mod matching;
+pub mod report;
+pub mod torrent;
+pub mod util;
+pub mod verify;
@@ -1,28 +1,31 @@
-mod client;
-mod fs;
-mod torrent;
-mod util;
-
-use anyhow::{anyhow, bail, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
 use console::style;
-use indicatif::ParallelProgressIterator;
-use rand::seq::SliceRandom;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use sha1_smol::Sha1;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use pollinators::{config, cross_seed, fs, journal, matching, report, util, verify};
+use std::io::Write;
+use std::path::PathBuf;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Path to the config file. Defaults to `$XDG_CONFIG_HOME/pollinators/config.toml`.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Which profile from the config file to use.
+    #[arg(long)]
+    profile: Option<String>,
+
     /// Where to look for potential matches. May be specified multiple times.
     #[arg(long)]
     source_dir: Vec<PathBuf>,
 
     /// Where to create the symlinks, if needed.
     #[arg(long)]
-    target_dir: PathBuf,
+    target_dir: Option<PathBuf>,
 
     /// If true, only prints out the changes that would have been made.
     #[arg(long)]
@@ -32,502 +35,311 @@ struct Args {
     #[arg(long)]
     skip_add: bool,
 
-    /// How many pieces should be tested per file when checking for a match.
-    #[arg(long, default_value_t = 3)]
-    pieces_to_test: usize,
+    /// Adds torrents to the client even if the config file sets `skip_add`.
+    #[arg(long, conflicts_with = "skip_add")]
+    no_skip_add: bool,
 
-    torrents: Vec<PathBuf>,
-}
+    /// How many pieces to test per file on average in a sampled check. The total budget, this
+    /// times the number of files, is spread across files in proportion to their size. Each
+    /// file's first and last piece are always checked and count towards it. Defaults to 3.
+    #[arg(long)]
+    pieces_to_test: Option<usize>,
 
-fn enumerate_files_with_sizes<P: AsRef<Path>>(dirs: &[P]) -> HashMap<u64, Vec<PathBuf>> {
-    let mut results = HashMap::<_, Vec<_>>::new();
-    let bar = util::new_spinner();
-    bar.enable_steady_tick(std::time::Duration::from_millis(125));
-    for dir in dirs {
-        bar.set_message(format!("enumerating {}", dir.as_ref().display()));
-        for entry in walkdir::WalkDir::new(dir) {
-            let Ok(entry) = entry else {
-                // TODO: error handling?
-                continue;
-            };
-            // TODO: handle symlinks?
-            if !entry.file_type().is_file() {
-                continue;
-            }
-            let Ok(metadata) = entry.metadata() else {
-                // TODO: error handling?
-                continue;
-            };
-            results
-                .entry(metadata.len())
-                .or_default()
-                .push(entry.into_path());
-        }
-    }
-    bar.finish_with_message(format!("enumerated {} files", results.len()));
-    results
-}
+    /// Percentage of pieces to test when checking for a match. Overrides `--pieces-to-test`.
+    #[arg(long)]
+    sample_coverage: Option<f64>,
 
-trait CheckWithFileMapping {
-    fn check(&self, mapping: &HashMap<&Path, &Path>) -> Result<bool>;
-}
+    /// Seed for choosing which pieces to test, to repeat an earlier check. Defaults to a random
+    /// seed, which is included in JSON output.
+    #[arg(long)]
+    seed: Option<u64>,
 
-impl CheckWithFileMapping for torrent::Piece {
-    fn check(&self, mapping: &HashMap<&Path, &Path>) -> Result<bool> {
-        let mut sha1 = Sha1::new();
-        for slice in &self.file_slices {
-            let file = File::open(
-                mapping
-                    .get::<Path>(slice.path.as_ref())
-                    .ok_or_else(|| anyhow!("no mapping for {}", slice.path.display()))?,
-            )?;
-            let mut buffer = vec![0; slice.length.try_into()?];
-            let bytes_read = rustix::io::pread(file, &mut buffer, slice.offset)?;
-            if bytes_read as u64 != slice.length {
-                bail!(
-                    "pread failed for {}: read {} bytes at offset {} instead of {} bytes",
-                    slice.path.display(),
-                    bytes_read,
-                    slice.offset,
-                    slice.length
-                );
-            }
-            sha1.update(&buffer);
-        }
-        Ok(sha1.digest().bytes() == self.hash.bytes())
-    }
-}
+    /// How to check matched source files. Defaults to `none` with `--trust-client`, `sample` with
+    /// `--dry-run` or `--skip-add`, and `full` otherwise.
+    #[arg(long, value_enum)]
+    verify: Option<verify::VerifyMode>,
 
-trait CrossSeed {
-    fn base_dir(&self, target_dir: &Path) -> Result<PathBuf>;
-    fn cross_seed(
-        &self,
-        dry_run: bool,
-        skip_add: bool,
-        path: &Path,
-        target_dir: &Path,
-        candidates: &HashMap<&Path, &Path>,
-    ) -> Result<()>;
-}
+    /// If true, the client hash checks torrents when adding them, instead of importing them as
+    /// already complete.
+    #[arg(long)]
+    trust_client: bool,
 
-impl CrossSeed for torrent::Torrent {
-    fn base_dir(&self, target_dir: &Path) -> Result<PathBuf> {
-        Ok(target_dir.join(
-            url::Url::parse(&self.announce)?
-                .host_str()
-                .ok_or_else(|| anyhow!("announce URL {} has no hostname", self.announce))?,
-        ))
-    }
+    /// Imports torrents as already complete even if the config file sets `trust_client`.
+    #[arg(long, conflicts_with = "trust_client")]
+    no_trust_client: bool,
 
-    fn cross_seed(
-        &self,
-        dry_run: bool,
-        skip_add: bool,
-        path: &Path,
-        target_dir: &Path,
-        candidates: &HashMap<&Path, &Path>,
-    ) -> Result<()> {
-        if self.info.is_single_file {
-            let (source, target) = candidates.iter().next().unwrap();
-            return if *source == target.file_name().unwrap() {
-                println!(
-                    "torrent can be directly seeded from {}",
-                    target.parent().unwrap().display()
-                );
-                if !skip_add {
-                    client::new_instance(dry_run).add_torrent(path, target.parent().unwrap())?;
-                }
-                Ok(())
-            } else {
-                let base_dir = self.base_dir(target_dir)?;
-                println!(
-                    "{} {}",
-                    style("found matches with different filenames; creating symlinks in").blue(),
-                    base_dir.display()
-                );
-                let fs = fs::new_instance(dry_run);
-                fs.create_dir_all(&base_dir)?;
-                fs.symlink(target, &base_dir.join(source))?;
-                if !skip_add {
-                    client::new_instance(dry_run).add_torrent(path, &base_dir)
-                } else {
-                    Ok(())
-                }
-            };
-        }
+    /// How to read source files when hash checking. Defaults to `pread`.
+    #[arg(long, value_enum)]
+    read_mode: Option<verify::ReadMode>,
 
-        // Check if symlinks are needed at all; if the same prefix can be used for all selected
-        // candidate paths, then a symlink is sufficient.
-        let path_prefix: HashSet<Option<PathBuf>> = candidates
-            .iter()
-            .map(|(source, target)| target.remove_common_suffix(source))
-            .collect();
-        if !path_prefix.contains(&None) && path_prefix.len() == 1 {
-            let seed_path = path_prefix.into_iter().next().unwrap().unwrap();
-            println!(
-                "torrent can be directly seeded from {}",
-                seed_path.display()
-            );
-            if !skip_add {
-                client::new_instance(dry_run).add_torrent(path, &seed_path)?;
-            }
-            return Ok(());
-        }
-        let base_dir = self.base_dir(target_dir)?;
-        println!(
-            "{} {}",
-            style("found matches with different filenames; creating symlinks in").blue(),
-            base_dir.display()
-        );
-        let fs = fs::new_instance(dry_run);
-        for (source_path, target_path) in candidates {
-            if let Some(parent) = source_path.parent() {
-                fs.create_dir_all(&base_dir.join(parent))?;
-            }
-            fs.symlink(target_path, &base_dir.join(source_path))?;
-        }
-        if !skip_add {
-            client::new_instance(dry_run).add_torrent(path, &base_dir)?;
-        }
+    /// How many times to retry reading a source file after a transient I/O error. Defaults to 2.
+    #[arg(long)]
+    read_retries: Option<u32>,
 
-        Ok(())
-    }
-}
+    /// How long to wait between read retries, in milliseconds. Defaults to 500.
+    #[arg(long)]
+    read_retry_delay_ms: Option<u64>,
 
-trait PathHelper {
-    fn remove_common_suffix(&self, suffix: &Self) -> Option<PathBuf>;
-}
+    /// How many workers may read concurrently from a spinning disk. Defaults to 1.
+    #[arg(long)]
+    hdd_concurrency: Option<usize>,
 
-impl PathHelper for Path {
-    fn remove_common_suffix(&self, suffix: &Path) -> Option<PathBuf> {
-        let mut self_components = self.components().rev();
-        let mut suffix_components = suffix.components().rev();
-        loop {
-            match (self_components.next(), suffix_components.next()) {
-                (Some(x), Some(y)) if x == y => continue,
-                (Some(x), None) => {
-                    return Some(self_components.rev().chain(Some(x)).collect());
-                }
-                _ => return None,
-            }
-        }
-    }
+    /// How many workers may read concurrently from an SSD or other non-rotational device. Defaults
+    /// to unlimited.
+    #[arg(long)]
+    ssd_concurrency: Option<usize>,
+
+    /// Limit on how fast source files are read across all devices, in bytes per second.
+    #[arg(long)]
+    max_read_rate: Option<u64>,
+
+    /// Limit on how fast source files are read from each device, in bytes per second.
+    #[arg(long)]
+    max_device_read_rate: Option<u64>,
+
+    /// The I/O priority of hashing threads. Defaults to `normal`.
+    #[arg(long, value_enum)]
+    io_priority: Option<verify::IoPriority>,
+
+    /// Where to save progress of full hash checks, so they can be resumed if interrupted. Defaults
+    /// to `$XDG_STATE_HOME/pollinators/checkpoints`.
+    #[arg(long)]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// If true, full hash checks always start from scratch and do not save progress.
+    #[arg(long)]
+    no_checkpoint: bool,
+
+    /// How many torrents to verify and cross-seed concurrently. Defaults to 1.
+    #[arg(long, short)]
+    jobs: Option<usize>,
+
+    /// What kind of links to create when files need to be renamed. Defaults to symlinks.
+    #[arg(long, value_enum)]
+    link_type: Option<fs::LinkType>,
+
+    /// Where to create links under the target dir. Placeholders: `{tracker}`, `{host}`,
+    /// `{category}`, `{name}`, `{infohash}`, `{infohash8}` and `{source}`. Defaults to
+    /// `{tracker}`.
+    #[arg(long)]
+    layout: Option<cross_seed::Layout>,
+
+    /// Substituted for `{category}` in the layout.
+    #[arg(long)]
+    category: Option<String>,
+
+    /// What to do when a link's destination already exists but is not a link to the matched
+    /// file. Defaults to `fail`.
+    #[arg(long, value_enum)]
+    on_conflict: Option<fs::OnConflict>,
+
+    /// How to report results. Machine-readable formats are written to stdout; status messages are
+    /// written to stderr instead.
+    #[arg(long, value_enum, default_value_t)]
+    output: report::OutputFormat,
+
+    torrents: Vec<PathBuf>,
 }
 
-fn get_best_candidate<'a, P, Q>(
-    path: &'a Path,
-    candidates: &'a [P],
-    preferred_prefix: Option<&Q>,
-) -> Option<(&'a Path, &'a Path)>
-where
-    P: AsRef<Path> + Ord,
-    Q: AsRef<Path> + ?Sized,
-{
-    let candidate = candidates
-        .iter()
-        .map(|candidate| {
-            let common_suffix = candidate
-                .as_ref()
-                .iter()
-                .rev()
-                .zip(path.iter().rev())
-                .take_while(|(x, y)| x == y)
-                .count();
-            let common_prefix = preferred_prefix.as_ref().map_or(0, |path| {
-                candidate
-                    .as_ref()
-                    .iter()
-                    .zip(path.as_ref().iter())
-                    .take_while(|(x, y)| x == y)
-                    .count()
-            });
-            (common_suffix, common_prefix, candidate)
-        })
-        .max()?;
-    Some((path, candidate.2.as_ref()))
+#[derive(Subcommand)]
+enum Command {
+    /// Removes links and directories created by an earlier run, as recorded in the journal.
+    /// Anything modified since it was created is left in place.
+    Undo(UndoArgs),
+    /// Prints the metadata of torrent files, with tracker URLs redacted.
+    Inspect(InspectArgs),
 }
 
-fn pick_candidates<'a>(
-    candidates: HashMap<(&'a PathBuf, u64), &'a Vec<PathBuf>>,
-) -> HashMap<&'a Path, &'a Path> {
-    // Heuristic: If the file with the largest size has a single unique match, prefer matches that
-    // share a common prefix.
-    let largest_file_candidate_path = candidates
-        .iter()
-        .max_by_key(|((_path, len), _candidates)| len)
-        .and_then(|(_, candidates)| {
-            if candidates.len() == 1 {
-                candidates.iter().next()
-            } else {
-                None
-            }
-        });
-    // TODO: This doesn't prevent duplicate assignments, which is probably not desirable.
-    candidates
-        .into_iter()
-        .map(|((path, _len), candidates)| {
-            get_best_candidate(path, candidates, largest_file_candidate_path.as_ref()).unwrap()
-        })
-        .collect()
+#[derive(clap::Args)]
+struct InspectArgs {
+    /// How to print metadata.
+    #[arg(long, value_enum, default_value_t)]
+    output: report::OutputFormat,
+
+    torrents: Vec<PathBuf>,
 }
 
-fn process_torrent(
-    path: &Path,
-    target_dir: &Path,
-    entries: &HashMap<u64, Vec<PathBuf>>,
-    pieces_to_test: usize,
-    dry_run: bool,
-    skip_add: bool,
-) -> Result<()> {
-    let torrent: torrent::Torrent = serde_bencode::from_bytes(&std::fs::read(path)?)?;
-    println!("processing {}", path.display());
-    // By definition, potential candidates must have matching file sizes.
-    let candidates = torrent
-        .info
-        .files
-        .iter()
-        .map(|file| {
-            let Some(entry) = entries.get(&file.length) else {
-                bail!(
-                    "unable to find candidate matches for file {} with size {}",
-                    file.path.display(),
-                    file.length
-                );
-            };
-            Ok(((&file.path, file.length), entry))
-        })
-        .collect::<Result<HashMap<_, _>, _>>()?;
-    let candidates = pick_candidates(candidates);
-    let pieces = if dry_run || skip_add {
-        // Sample a number of pieces to file as a quick correctness check.
-        let mut path_to_pieces = HashMap::<_, Vec<_>>::new();
-        for piece in &torrent.info.pieces {
-            for slice in &piece.file_slices {
-                path_to_pieces.entry(&slice.path).or_default().push(piece);
-            }
-        }
-        std::borrow::Cow::Owned(
-            path_to_pieces
-                .into_iter()
-                .flat_map(|(_path, mut pieces)| {
-                    let piece_count = std::cmp::min(pieces_to_test, pieces.len());
-                    pieces.shuffle(&mut rand::rng());
-                    pieces.truncate(piece_count);
-                    pieces
-                })
-                .collect::<HashSet<_>>()
-                .into_iter()
-                .cloned()
-                .collect::<Vec<_>>(),
-        )
-    } else {
-        // Otherwise, do a full check: the hash checks are parallelized and can run faster than
-        // hash checks in many common torrent clients.
-        std::borrow::Cow::Borrowed(&torrent.info.pieces)
-    };
-    let bar = util::new_bar(pieces.len() as u64).with_message("hashing...");
-    let failed_paths: HashSet<_> = pieces
-        .par_iter()
-        .progress_with(bar)
-        // TODO: Probably want some sort of error handling here.
-        .filter(|piece| !piece.check(&candidates).unwrap())
-        .flat_map_iter(|piece| piece.file_slices.iter().map(|slice| &slice.path))
-        .collect();
-    if !failed_paths.is_empty() {
-        let failed_paths = failed_paths.into_iter().collect::<BTreeSet<_>>();
-        let candidates = candidates.into_iter().collect::<BTreeMap<_, _>>();
-        bail!("hash check failed for paths: {failed_paths:#?}\n\ncandidates: {candidates:#?}");
-    }
+#[derive(clap::Args)]
+struct UndoArgs {
+    /// Path to the config file. Defaults to `$XDG_CONFIG_HOME/pollinators/config.toml`.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Which profile from the config file to use.
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Path to the journal. Defaults to `$XDG_STATE_HOME/pollinators/journal.jsonl`.
+    #[arg(long)]
+    journal: Option<PathBuf>,
 
-    torrent.cross_seed(dry_run, skip_add, path, target_dir, &candidates)
+    /// Which run to undo. Defaults to the most recent run, or every run if `--torrent` is set.
+    #[arg(long)]
+    run: Option<String>,
+
+    /// Only undo changes made for the torrent with this info-hash or path.
+    #[arg(long)]
+    torrent: Option<String>,
+
+    /// If true, only prints out what would have been removed.
+    #[arg(long)]
+    dry_run: bool,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let entries = enumerate_files_with_sizes(&args.source_dir);
-    for torrent in args.torrents {
-        if let Err(err) = process_torrent(
-            &torrent,
-            &args.target_dir,
-            &entries,
-            args.pieces_to_test,
-            args.dry_run,
-            args.skip_add,
-        ) {
-            println!("{} {:?}", style("error:").red(), style(err).red());
+impl Args {
+    /// Options set on the command line, which take precedence over the config file.
+    fn to_profile(&self) -> config::Profile {
+        config::Profile {
+            source_dirs: self.source_dir.clone(),
+            target_dir: self.target_dir.clone(),
+            pieces_to_test: self.pieces_to_test,
+            sample_coverage: self.sample_coverage,
+            seed: self.seed,
+            verify: self.verify,
+            trust_client: flag(self.trust_client, self.no_trust_client),
+            link_type: self.link_type,
+            layout: self.layout.clone(),
+            category: self.category.clone(),
+            on_conflict: self.on_conflict,
+            skip_add: flag(self.skip_add, self.no_skip_add),
+            read_mode: self.read_mode,
+            read_retries: self.read_retries,
+            read_retry_delay_ms: self.read_retry_delay_ms,
+            hdd_concurrency: self.hdd_concurrency,
+            ssd_concurrency: self.ssd_concurrency,
+            max_read_rate: self.max_read_rate,
+            max_device_read_rate: self.max_device_read_rate,
+            io_priority: self.io_priority,
+            checkpoint_dir: self.checkpoint_dir.clone(),
+            checkpoint: self.no_checkpoint.then_some(false),
+            jobs: self.jobs,
+            ..Default::default()
         }
     }
-    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn remove_common_suffix_all_unique() {
-        // Absolute
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("/d/e")),
-            None
-        );
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("/d/e/f")),
-            None
-        );
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("/d/e/f/g")),
-            None
-        );
-
-        // Relative
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("d/e")),
-            None
-        );
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("d/e/f")),
-            None
-        );
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("d/e/f/g")),
-            None
-        );
-    }
-
-    #[test]
-    fn remove_common_suffix_partial_shared() {
-        // TODO: This doesn't seem quite right, but the logic seems to more or less work for now...
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("/b/c")),
-            None,
-        );
-
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("b/c")),
-            Some("/a".into()),
-        );
+/// Combines a flag and its negation into an override, if either was given.
+fn flag(set: bool, unset: bool) -> Option<bool> {
+    match (set, unset) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
     }
+}
 
-    #[test]
-    fn remove_common_suffix_all_shared() {
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("/a/b/c")),
-            None,
-        );
-
-        assert_eq!(
-            Path::new("/a/b/c").remove_common_suffix(Path::new("a/b/c")),
-            Some("/".into()),
-        );
-    }
+fn load_settings(args: &Args) -> Result<config::Settings> {
+    let config = config::Config::load(args.config.as_deref())?;
+    let profile = config
+        .profile(args.profile.as_deref())?
+        .merge(args.to_profile());
+    let mut settings = config::Settings::new(profile, args.dry_run)?;
+    settings.output = args.output.status_output();
+    Ok(settings)
+}
 
-    #[test]
-    fn get_best_candidate_single_option() {
-        assert_eq!(
-            get_best_candidate(Path::new("b/c"), &vec![Path::new("/a/b/c")], None::<&Path>),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
-
-        // With only a single option, `preferred_prefix` should have no effect on the result.
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c")],
-                Some(&Path::new("/a2/b2/c2"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
-
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c")],
-                Some(&Path::new("/a/b/c"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
+fn undo(args: &UndoArgs) -> Result<report::ExitCode> {
+    let journal = match &args.journal {
+        Some(journal) => journal.clone(),
+        None => config::Config::load(args.config.as_deref())?
+            .profile(args.profile.as_deref())?
+            .journal
+            .or_else(journal::default_path)
+            .context("unable to determine journal path")?,
+    };
+    let entries = journal::load(&journal)
+        .with_context(|| format!("unable to read journal {}", journal.display()))?;
+    let filter = journal::Filter {
+        run: args.run.clone(),
+        torrent: args.torrent.clone(),
+    };
+    let selected = filter.select(&entries);
+    if selected.is_empty() {
+        bail!("no matching changes in journal {}", journal.display());
     }
+    let summary = journal::undo(&selected, args.dry_run, util::Output::Stdout);
+    eprintln!(
+        "{} reverted, {} already gone, {} left in place",
+        summary.reverted, summary.missing, summary.refused
+    );
+    Ok(if summary.refused == 0 {
+        report::ExitCode::Success
+    } else {
+        report::ExitCode::SomeFailed
+    })
+}
 
-    #[test]
-    fn get_best_candidate_preferred_prefix_disambiguates() {
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a2/b/c")],
-                Some(&Path::new("/a"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
-
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a2/b/c")],
-                Some(&Path::new("/a/b"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
-
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a2/b/c")],
-                Some(&Path::new("/a/b2"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
+fn inspect(args: &InspectArgs) -> Result<report::ExitCode> {
+    let mut code = report::ExitCode::Success;
+    let mut torrents = vec![];
+    for path in &args.torrents {
+        match cross_seed::load_torrent(path) {
+            Ok(torrent) => torrents.push((path, torrent)),
+            Err(err) => {
+                eprintln!("{} {}", style("error:").red(), style(err).red());
+                code = report::ExitCode::SomeFailed;
+            }
+        }
     }
-
-    #[test]
-    fn get_best_candidate_preferred_prefix_matches_nothing() {
-        // The implementation takes the max tuple candidate; in this case, only the final path
-        // component of the tuple will differ, so the implementation will return the "max" path.
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a2/b/c")],
-                Some(&Path::new("/e"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a2/b/c")))
-        );
+    let details = torrents
+        .iter()
+        .map(|(path, torrent)| report::TorrentDetails::new(path, torrent));
+    let mut stdout = std::io::stdout().lock();
+    match args.output {
+        report::OutputFormat::Text => {
+            for details in details {
+                writeln!(stdout, "{details}")?;
+            }
+        }
+        report::OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &details.collect::<Vec<_>>())?;
+            writeln!(stdout)?;
+        }
+        report::OutputFormat::Jsonl => {
+            for details in details {
+                serde_json::to_writer(&mut stdout, &details)?;
+                writeln!(stdout)?;
+            }
+        }
     }
+    Ok(code)
+}
 
-    #[test]
-    fn get_best_candidate_longest_shared_suffix_wins() {
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a/b2/c")],
-                None::<&Path>,
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
+fn main() -> std::process::ExitCode {
+    let args = Args::parse();
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::Undo(undo_args) => undo(undo_args),
+            Command::Inspect(inspect_args) => inspect(inspect_args),
+        };
+        return match result {
+            Ok(code) => code.into(),
+            Err(err) => {
+                eprintln!("{} {:?}", style("error:").red(), style(err).red());
+                report::ExitCode::Fatal.into()
+            }
+        };
     }
-
-    #[test]
-    fn get_best_candidate_shared_longest_shared_suffix() {
-        // The implementation takes the max tuple candidate; in this case, only the final path
-        // component of the tuple will differ, so the implementation will return the "max" path.
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a2/b/c")],
-                None::<&Path>,
-            ),
-            Some((Path::new("b/c"), Path::new("/a2/b/c")))
-        );
+    let settings = match load_settings(&args) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{} {:?}", style("error:").red(), style(err).red());
+            return report::ExitCode::Fatal.into();
+        }
+    };
+    let entries = matching::enumerate_files_with_sizes(&settings.source_dirs, settings.output);
+    let mut reporter = report::Reporter::new(args.output);
+    let result = cross_seed::process_batch(&args.torrents, &settings, &entries, |report| {
+        reporter.report(report)
+    });
+    if let Err(err) = result {
+        eprintln!("{} {:?}", style("error:").red(), style(err).red());
+        return report::ExitCode::Fatal.into();
     }
-
-    #[test]
-    fn get_best_candidate_prefer_suffix_over_prefix() {
-        assert_eq!(
-            get_best_candidate(
-                Path::new("b/c"),
-                &vec![Path::new("/a/b/c"), Path::new("/a/b2/c")],
-                Some(&Path::new("/a/b2"))
-            ),
-            Some((Path::new("b/c"), Path::new("/a/b/c")))
-        );
+    match reporter.finish() {
+        Ok(code) => code.into(),
+        Err(err) => {
+            eprintln!("{} {:?}", style("error:").red(), style(err).red());
+            report::ExitCode::Fatal.into()
+        }
     }
 }
@@ -0,0 +1,310 @@
+use crate::error::{Error, Result};
+use crate::torrent;
+use crate::util;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Recursively lists the regular files in `dirs`, keyed by file size, showing progress on
+/// `output`.
+pub fn enumerate_files_with_sizes<P: AsRef<Path>>(
+    dirs: &[P],
+    output: util::Output,
+) -> HashMap<u64, Vec<PathBuf>> {
+    let mut results = HashMap::<_, Vec<_>>::new();
+    let bar = util::new_spinner(output);
+    bar.enable_steady_tick(std::time::Duration::from_millis(125));
+    for dir in dirs {
+        bar.set_message(format!("enumerating {}", dir.as_ref().display()));
+        for entry in walkdir::WalkDir::new(dir) {
+            let Ok(entry) = entry else {
+                // TODO: error handling?
+                continue;
+            };
+            // TODO: handle symlinks?
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else {
+                // TODO: error handling?
+                continue;
+            };
+            results
+                .entry(metadata.len())
+                .or_default()
+                .push(entry.into_path());
+        }
+    }
+    bar.finish_with_message(format!("enumerated {} files", results.len()));
+    results
+}
+
+pub trait PathHelper {
+    fn remove_common_suffix(&self, suffix: &Self) -> Option<PathBuf>;
+}
+
+impl PathHelper for Path {
+    fn remove_common_suffix(&self, suffix: &Path) -> Option<PathBuf> {
+        let mut self_components = self.components().rev();
+        let mut suffix_components = suffix.components().rev();
+        loop {
+            match (self_components.next(), suffix_components.next()) {
+                (Some(x), Some(y)) if x == y => continue,
+                (Some(x), None) => {
+                    return Some(self_components.rev().chain(Some(x)).collect());
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub fn get_best_candidate<'a, P, Q>(
+    path: &'a Path,
+    candidates: &'a [P],
+    preferred_prefix: Option<&Q>,
+) -> Option<(&'a Path, &'a Path)>
+where
+    P: AsRef<Path> + Ord,
+    Q: AsRef<Path> + ?Sized,
+{
+    let candidate = candidates
+        .iter()
+        .map(|candidate| {
+            let common_suffix = candidate
+                .as_ref()
+                .iter()
+                .rev()
+                .zip(path.iter().rev())
+                .take_while(|(x, y)| x == y)
+                .count();
+            let common_prefix = preferred_prefix.as_ref().map_or(0, |path| {
+                candidate
+                    .as_ref()
+                    .iter()
+                    .zip(path.as_ref().iter())
+                    .take_while(|(x, y)| x == y)
+                    .count()
+            });
+            (common_suffix, common_prefix, candidate)
+        })
+        .max()?;
+    Some((path, candidate.2.as_ref()))
+}
+
+pub fn pick_candidates<'a>(
+    candidates: HashMap<(&'a PathBuf, u64), &'a Vec<PathBuf>>,
+) -> HashMap<&'a Path, &'a Path> {
+    // Heuristic: If the file with the largest size has a single unique match, prefer matches that
+    // share a common prefix.
+    let largest_file_candidate_path = candidates
+        .iter()
+        .max_by_key(|((_path, len), _candidates)| len)
+        .and_then(|(_, candidates)| {
+            if candidates.len() == 1 {
+                candidates.iter().next()
+            } else {
+                None
+            }
+        });
+    // TODO: This doesn't prevent duplicate assignments, which is probably not desirable.
+    candidates
+        .into_iter()
+        .map(|((path, _len), candidates)| {
+            get_best_candidate(path, candidates, largest_file_candidate_path.as_ref()).unwrap()
+        })
+        .collect()
+}
+
+/// Finds a source file for every file in `info`. By definition, potential candidates must have
+/// matching file sizes.
+pub fn find_candidates<'a>(
+    info: &'a torrent::Info,
+    entries: &'a HashMap<u64, Vec<PathBuf>>,
+) -> Result<HashMap<&'a Path, &'a Path>> {
+    let candidates = info
+        .files
+        .iter()
+        .map(|file| {
+            let Some(entry) = entries.get(&file.length) else {
+                return Err(Error::NoSizeMatch {
+                    path: file.path.clone(),
+                    length: file.length,
+                });
+            };
+            Ok(((&file.path, file.length), entry))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    Ok(pick_candidates(candidates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_common_suffix_all_unique() {
+        // Absolute
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("/d/e")),
+            None
+        );
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("/d/e/f")),
+            None
+        );
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("/d/e/f/g")),
+            None
+        );
+
+        // Relative
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("d/e")),
+            None
+        );
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("d/e/f")),
+            None
+        );
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("d/e/f/g")),
+            None
+        );
+    }
+
+    #[test]
+    fn remove_common_suffix_partial_shared() {
+        // TODO: This doesn't seem quite right, but the logic seems to more or less work for now...
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("/b/c")),
+            None,
+        );
+
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("b/c")),
+            Some("/a".into()),
+        );
+    }
+
+    #[test]
+    fn remove_common_suffix_all_shared() {
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("/a/b/c")),
+            None,
+        );
+
+        assert_eq!(
+            Path::new("/a/b/c").remove_common_suffix(Path::new("a/b/c")),
+            Some("/".into()),
+        );
+    }
+
+    #[test]
+    fn get_best_candidate_single_option() {
+        assert_eq!(
+            get_best_candidate(Path::new("b/c"), &[Path::new("/a/b/c")], None::<&Path>),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+
+        // With only a single option, `preferred_prefix` should have no effect on the result.
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c")],
+                Some(&Path::new("/a2/b2/c2"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c")],
+                Some(&Path::new("/a/b/c"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+    }
+
+    #[test]
+    fn get_best_candidate_preferred_prefix_disambiguates() {
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a2/b/c")],
+                Some(&Path::new("/a"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a2/b/c")],
+                Some(&Path::new("/a/b"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a2/b/c")],
+                Some(&Path::new("/a/b2"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+    }
+
+    #[test]
+    fn get_best_candidate_preferred_prefix_matches_nothing() {
+        // The implementation takes the max tuple candidate; in this case, only the final path
+        // component of the tuple will differ, so the implementation will return the "max" path.
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a2/b/c")],
+                Some(&Path::new("/e"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a2/b/c")))
+        );
+    }
+
+    #[test]
+    fn get_best_candidate_longest_shared_suffix_wins() {
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a/b2/c")],
+                None::<&Path>,
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+    }
+
+    #[test]
+    fn get_best_candidate_shared_longest_shared_suffix() {
+        // The implementation takes the max tuple candidate; in this case, only the final path
+        // component of the tuple will differ, so the implementation will return the "max" path.
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a2/b/c")],
+                None::<&Path>,
+            ),
+            Some((Path::new("b/c"), Path::new("/a2/b/c")))
+        );
+    }
+
+    #[test]
+    fn get_best_candidate_prefer_suffix_over_prefix() {
+        assert_eq!(
+            get_best_candidate(
+                Path::new("b/c"),
+                &[Path::new("/a/b/c"), Path::new("/a/b2/c")],
+                Some(&Path::new("/a/b2"))
+            ),
+            Some((Path::new("b/c"), Path::new("/a/b/c")))
+        );
+    }
+}
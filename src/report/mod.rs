@@ -0,0 +1,528 @@
+use crate::error::ErrorCategory;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable, colored output.
+    #[default]
+    Text,
+    /// A single JSON array containing every torrent's result, written at the end of the run.
+    Json,
+    /// One JSON object per line, written as each torrent finishes.
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// Where status messages go alongside this format: stdout for text, or stderr so they do not
+    /// mix with machine-readable output.
+    pub fn status_output(self) -> crate::util::Output {
+        match self {
+            OutputFormat::Text => crate::util::Output::Stdout,
+            OutputFormat::Json | OutputFormat::Jsonl => crate::util::Output::Stderr,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// The matched files can be seeded as-is from an existing directory.
+    SeededDirectly,
+    /// Links were created in the target directory to seed from.
+    Linked,
+    /// Every link needed to seed the torrent already existed in the target directory.
+    AlreadyPresent,
+    /// No matching source files were found.
+    NoMatch,
+    /// Source files were found, but their contents did not match.
+    HashFailed,
+    /// The torrent has not finished processing, or failed for any other reason.
+    #[default]
+    Failed,
+}
+
+impl Status {
+    pub fn is_success(self) -> bool {
+        matches!(
+            self,
+            Status::SeededDirectly | Status::Linked | Status::AlreadyPresent
+        )
+    }
+
+    /// The status of a torrent that failed with this category of error.
+    pub fn from_error(category: ErrorCategory) -> Status {
+        match category {
+            ErrorCategory::NoSizeMatch => Status::NoMatch,
+            ErrorCategory::HashMismatch => Status::HashFailed,
+            ErrorCategory::Parse
+            | ErrorCategory::Io
+            | ErrorCategory::Link
+            | ErrorCategory::Policy
+            | ErrorCategory::ClientUnavailable
+            | ErrorCategory::ClientRejected => Status::Failed,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Status::SeededDirectly => "seeded directly",
+            Status::Linked => "linked",
+            Status::AlreadyPresent => "already present",
+            Status::NoMatch => "no match",
+            Status::HashFailed => "hash failed",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorReport {
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkRecord {
+    pub link: PathBuf,
+    pub original: PathBuf,
+    pub link_type: crate::fs::LinkType,
+}
+
+/// An existing entry found where a link needed to be created.
+#[derive(Debug, Serialize)]
+pub struct ConflictRecord {
+    pub path: PathBuf,
+    pub resolution: crate::fs::OnConflict,
+    /// Where the existing entry was moved to, if it was renamed.
+    pub renamed_to: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct HashCheckStats {
+    /// Whether every piece was checked, or only a sample.
+    pub full: bool,
+    /// The seed used to sample pieces, if only a sample was checked.
+    pub seed: Option<u64>,
+    pub pieces_total: usize,
+    pub pieces_checked: usize,
+    /// Checked pieces that were already verified by an earlier, interrupted run.
+    pub pieces_resumed: usize,
+    /// Checked pieces whose result was shared with another torrent covering the same data.
+    pub pieces_shared: usize,
+    /// Pieces whose contents did not match the expected hash.
+    pub pieces_failed: usize,
+    /// Pieces that could not be read, e.g. due to I/O errors or truncated files.
+    pub pieces_errored: usize,
+    pub bytes_checked: u64,
+    pub elapsed_ms: u128,
+}
+
+/// Hash check results for a single file in the torrent.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileCheck {
+    /// The path in the torrent.
+    pub path: PathBuf,
+    /// The source file it was matched with.
+    pub source: Option<PathBuf>,
+    pub pieces_checked: usize,
+    pub pieces_failed: usize,
+    pub pieces_errored: usize,
+    /// The byte range in the file spanned by failed pieces, from the start of the first to the end
+    /// of the last. Pieces spanning a file boundary count against both files.
+    pub bad_range: Option<(u64, u64)>,
+    pub bytes_checked: u64,
+    /// Bytes in pieces that matched their expected hash.
+    pub bytes_matched: u64,
+}
+
+impl FileCheck {
+    /// The percentage of checked bytes that matched.
+    pub fn percent_matching(&self) -> f64 {
+        if self.bytes_checked == 0 {
+            return 100.0;
+        }
+        self.bytes_matched as f64 * 100.0 / self.bytes_checked as f64
+    }
+}
+
+impl std::fmt::Display for FileCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(source) = &self.source {
+            write!(f, " (from {})", source.display())?;
+        }
+        write!(
+            f,
+            ": {}/{} pieces failed",
+            self.pieces_failed, self.pieces_checked
+        )?;
+        if self.pieces_errored > 0 {
+            write!(f, ", {} unreadable", self.pieces_errored)?;
+        }
+        write!(f, ", {:.1}% matching", self.percent_matching())?;
+        if let Some((start, end)) = self.bad_range {
+            write!(f, ", bad bytes {start}..{end}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of processing a single torrent.
+#[derive(Debug, Default, Serialize)]
+pub struct TorrentReport {
+    pub torrent: PathBuf,
+    pub status: Status,
+    pub info_hash: Option<String>,
+    pub name: Option<String>,
+    /// The registered name of the torrent's tracker, or else its announce hostname.
+    pub tracker: Option<String>,
+    /// Maps paths in the torrent to the source files they were matched with.
+    pub matched_files: BTreeMap<PathBuf, PathBuf>,
+    pub seed_path: Option<PathBuf>,
+    pub links_created: Vec<LinkRecord>,
+    pub conflicts: Vec<ConflictRecord>,
+    pub hash_check: Option<HashCheckStats>,
+    /// Per-file hash check results, in torrent order.
+    pub file_checks: Vec<FileCheck>,
+    pub error: Option<ErrorReport>,
+}
+
+impl TorrentReport {
+    pub fn new(torrent: &Path) -> TorrentReport {
+        TorrentReport {
+            torrent: torrent.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Marks the torrent as failed with `err`, and prints the error to `output`.
+    pub fn record_error(&mut self, err: &crate::error::Error, output: crate::util::Output) {
+        crate::status!(
+            output,
+            "{} {}",
+            console::style("error:").red(),
+            console::style(err).red()
+        );
+        self.status = Status::from_error(err.category());
+        self.error = Some(ErrorReport {
+            category: err.category(),
+            message: err.to_string(),
+        });
+    }
+}
+
+/// A one-line summary of the outcome, e.g. `a.torrent: linked (seeding from /target/tracker)`.
+impl std::fmt::Display for TorrentReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {}",
+            self.torrent.display(),
+            self.status.description()
+        )?;
+        if let Some(seed_path) = self.seed_path.as_ref().filter(|_| self.status.is_success()) {
+            write!(f, " (seeding from {})", seed_path.display())?;
+        }
+        if let Some(error) = &self.error {
+            write!(f, ": {}", error.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// A torrent's metadata, as shown by the `inspect` command. Tracker URLs are redacted, since they
+/// usually contain a passkey.
+#[derive(Debug, Serialize)]
+pub struct TorrentDetails<'a> {
+    pub torrent: &'a Path,
+    pub name: &'a str,
+    pub info_hash: String,
+    pub private: bool,
+    pub source: Option<&'a str>,
+    pub trackers: Vec<String>,
+    pub web_seeds: &'a [String],
+    pub comment: Option<&'a str>,
+    pub created_by: Option<&'a str>,
+    pub creation_date: Option<i64>,
+    pub encoding: Option<&'a str>,
+    pub piece_length: u64,
+    pub piece_count: usize,
+    pub total_length: u64,
+    pub files: &'a [crate::torrent::File],
+}
+
+impl TorrentDetails<'_> {
+    pub fn new<'a>(path: &'a Path, torrent: &'a crate::torrent::Torrent) -> TorrentDetails<'a> {
+        let mut trackers: Vec<_> = torrent.trackers().map(crate::torrent::redact_url).collect();
+        trackers.dedup();
+        TorrentDetails {
+            torrent: path,
+            name: &torrent.info.name,
+            info_hash: torrent.info_hash.to_string(),
+            private: torrent.info.private,
+            source: torrent.info.source.as_deref(),
+            trackers,
+            web_seeds: &torrent.url_list,
+            comment: torrent.comment.as_deref(),
+            created_by: torrent.created_by.as_deref(),
+            creation_date: torrent.creation_date,
+            encoding: torrent.encoding.as_deref(),
+            piece_length: torrent.info.piece_length,
+            piece_count: torrent.info.pieces.len(),
+            total_length: torrent.info.files.iter().map(|file| file.length).sum(),
+            files: &torrent.info.files,
+        }
+    }
+}
+
+impl std::fmt::Display for TorrentDetails<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.torrent.display())?;
+        writeln!(f, "  name:          {}", self.name)?;
+        writeln!(f, "  info hash:     {}", self.info_hash)?;
+        writeln!(
+            f,
+            "  private:       {}",
+            if self.private { "yes" } else { "no" }
+        )?;
+        let optional = [
+            ("source", self.source.map(str::to_owned)),
+            ("comment", self.comment.map(str::to_owned)),
+            ("created by", self.created_by.map(str::to_owned)),
+            ("creation date", self.creation_date.map(format_unix_time)),
+            ("encoding", self.encoding.map(str::to_owned)),
+        ];
+        for (label, value) in optional {
+            if let Some(value) = value {
+                writeln!(f, "  {:<14} {value}", format!("{label}:"))?;
+            }
+        }
+        for tracker in &self.trackers {
+            writeln!(f, "  tracker:       {tracker}")?;
+        }
+        for web_seed in self.web_seeds {
+            writeln!(f, "  web seed:      {web_seed}")?;
+        }
+        writeln!(
+            f,
+            "  pieces:        {} x {} bytes",
+            self.piece_count, self.piece_length
+        )?;
+        write!(
+            f,
+            "  files:         {} totalling {} bytes",
+            self.files.len(),
+            self.total_length
+        )?;
+        for file in self.files {
+            write!(f, "\n    {} ({} bytes)", file.path.display(), file.length)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+fn format_unix_time(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Converts days since the epoch to a civil date; see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+/// Process exit codes. Clap also exits with 2 for invalid arguments.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    /// Every torrent was seeded successfully.
+    Success = 0,
+    /// At least one torrent failed.
+    SomeFailed = 1,
+    /// The run could not start, e.g. due to invalid config.
+    Fatal = 2,
+    /// No torrent had any matching source files.
+    NothingMatched = 3,
+}
+
+impl From<ExitCode> for std::process::ExitCode {
+    fn from(code: ExitCode) -> std::process::ExitCode {
+        std::process::ExitCode::from(code as u8)
+    }
+}
+
+/// Counts of torrents by final status.
+#[derive(Debug, Default)]
+pub struct Summary {
+    counts: BTreeMap<Status, usize>,
+}
+
+impl Summary {
+    pub fn add(&mut self, status: Status) {
+        *self.counts.entry(status).or_default() += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    pub fn exit_code(&self) -> ExitCode {
+        let count = |status| self.counts.get(&status).copied().unwrap_or(0);
+        if self.counts.keys().all(|status| status.is_success()) {
+            ExitCode::Success
+        } else if count(Status::NoMatch) == self.total() {
+            ExitCode::NothingMatched
+        } else {
+            ExitCode::SomeFailed
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "summary:")?;
+        for status in [
+            Status::SeededDirectly,
+            Status::Linked,
+            Status::AlreadyPresent,
+            Status::NoMatch,
+            Status::HashFailed,
+            Status::Failed,
+        ] {
+            let count = self.counts.get(&status).copied().unwrap_or(0);
+            writeln!(f, "  {:<16} {count:>6}", status.description())?;
+        }
+        write!(f, "  {:<16} {:>6}", "total", self.total())
+    }
+}
+
+/// Writes torrent reports to stdout in the requested format.
+pub struct Reporter {
+    format: OutputFormat,
+    pending: Vec<TorrentReport>,
+    summary: Summary,
+}
+
+impl Reporter {
+    pub fn new(format: OutputFormat) -> Reporter {
+        Reporter {
+            format,
+            pending: vec![],
+            summary: Summary::default(),
+        }
+    }
+
+    pub fn report(&mut self, report: TorrentReport) -> std::io::Result<()> {
+        self.summary.add(report.status);
+        match self.format {
+            // Details are printed as processing happens, but with several jobs, lines from
+            // different torrents interleave. Each outcome is repeated here, in order.
+            OutputFormat::Text => {
+                let line = if report.status.is_success() {
+                    console::style(&report).green()
+                } else {
+                    console::style(&report).red()
+                };
+                crate::status!(self.format.status_output(), "{line}");
+                Ok(())
+            }
+            OutputFormat::Json => {
+                self.pending.push(report);
+                Ok(())
+            }
+            OutputFormat::Jsonl => {
+                let mut stdout = std::io::stdout().lock();
+                serde_json::to_writer(&mut stdout, &report)?;
+                writeln!(stdout)
+            }
+        }
+    }
+
+    /// Flushes any pending output, prints a summary, and returns the exit code for the run.
+    pub fn finish(self) -> std::io::Result<ExitCode> {
+        if self.format == OutputFormat::Json {
+            let mut stdout = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &self.pending)?;
+            writeln!(stdout)?;
+        }
+        crate::status!(self.format.status_output(), "{}", self.summary);
+        Ok(self.summary.exit_code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(statuses: &[Status]) -> Summary {
+        let mut summary = Summary::default();
+        for status in statuses {
+            summary.add(*status);
+        }
+        summary
+    }
+
+    #[test]
+    fn exit_code() {
+        assert_eq!(summary(&[]).exit_code(), ExitCode::Success);
+        assert_eq!(
+            summary(&[
+                Status::SeededDirectly,
+                Status::Linked,
+                Status::AlreadyPresent
+            ])
+            .exit_code(),
+            ExitCode::Success
+        );
+        assert_eq!(
+            summary(&[Status::NoMatch, Status::NoMatch]).exit_code(),
+            ExitCode::NothingMatched
+        );
+        assert_eq!(
+            summary(&[Status::NoMatch, Status::Linked]).exit_code(),
+            ExitCode::SomeFailed
+        );
+        assert_eq!(
+            summary(&[Status::NoMatch, Status::HashFailed]).exit_code(),
+            ExitCode::SomeFailed
+        );
+    }
+
+    #[test]
+    fn report_line() {
+        let mut report = TorrentReport::new(Path::new("a.torrent"));
+        report.status = Status::Linked;
+        report.seed_path = Some(PathBuf::from("/target/tracker"));
+        assert_eq!(
+            report.to_string(),
+            "a.torrent: linked (seeding from /target/tracker)"
+        );
+        report.status = Status::Failed;
+        report.error = Some(ErrorReport {
+            category: ErrorCategory::Io,
+            message: "disk on fire".into(),
+        });
+        assert_eq!(report.to_string(), "a.torrent: failed: disk on fire");
+    }
+
+    #[test]
+    fn unix_time() {
+        assert_eq!(format_unix_time(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_unix_time(951_827_696), "2000-02-29 12:34:56 UTC");
+        assert_eq!(format_unix_time(-1), "1969-12-31 23:59:59 UTC");
+    }
+}
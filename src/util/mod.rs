@@ -1,4 +1,11 @@
+#[allow(dead_code)]
 mod edit_distance;
+mod output;
 mod progress;
+#[cfg(test)]
+mod temp;
 
-pub use progress::{new_bar, new_spinner};
+pub use output::Output;
+pub use progress::{new_bar, new_spinner, suspend_progress};
+#[cfg(test)]
+pub use temp::TempDir;
@@ -0,0 +1,38 @@
+use std::io::Write;
+
+/// Where status messages and progress bars are written. Library functions take one of these
+/// instead of printing on their own, so that callers can keep them quiet.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Output {
+    /// Nothing is printed and no progress bars are drawn.
+    #[default]
+    Quiet,
+    /// Status messages are written to stdout.
+    Stdout,
+    /// Status messages are written to stderr, so that stdout is reserved for machine-readable
+    /// output.
+    Stderr,
+}
+
+impl Output {
+    /// Writes `bytes` as-is, e.g. the output of a child process.
+    pub fn write_all(self, bytes: &[u8]) -> std::io::Result<()> {
+        match self {
+            Output::Quiet => Ok(()),
+            Output::Stdout => std::io::stdout().write_all(bytes),
+            Output::Stderr => std::io::stderr().write_all(bytes),
+        }
+    }
+}
+
+/// Like `println!`, but writes to an `Output` and does not garble progress bars.
+#[macro_export]
+macro_rules! status {
+    ($output:expr, $($arg:tt)*) => {
+        match $output {
+            $crate::util::Output::Quiet => (),
+            $crate::util::Output::Stdout => $crate::util::suspend_progress(|| println!($($arg)*)),
+            $crate::util::Output::Stderr => $crate::util::suspend_progress(|| eprintln!($($arg)*)),
+        }
+    };
+}
@@ -1,7 +1,23 @@
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use super::Output;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressFinish, ProgressStyle};
+use std::sync::LazyLock;
 
-pub fn new_spinner() -> ProgressBar {
-    ProgressBar::new_spinner()
+/// Every progress bar is drawn through this, so bars for torrents processed concurrently stack
+/// instead of overwriting each other.
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+/// Runs `f` with progress bars hidden, so that it can print without garbling them.
+pub fn suspend_progress<R>(f: impl FnOnce() -> R) -> R {
+    PROGRESS.suspend(f)
+}
+
+/// A spinner drawn alongside `output`, or a hidden one if it is quiet.
+pub fn new_spinner(output: Output) -> ProgressBar {
+    if output == Output::Quiet {
+        return ProgressBar::hidden();
+    }
+    PROGRESS
+        .add(ProgressBar::new_spinner())
         .with_style(
             ProgressStyle::with_template("[{spinner:20.cyan/blue}] {msg}")
                 .unwrap()
@@ -35,8 +51,13 @@ pub fn new_spinner() -> ProgressBar {
         .with_finish(ProgressFinish::AndLeave)
 }
 
-pub fn new_bar(len: u64) -> ProgressBar {
-    ProgressBar::new(len)
+/// A progress bar drawn alongside `output`, or a hidden one if it is quiet.
+pub fn new_bar(output: Output, len: u64) -> ProgressBar {
+    if output == Output::Quiet {
+        return ProgressBar::with_draw_target(Some(len), ProgressDrawTarget::hidden());
+    }
+    PROGRESS
+        .add(ProgressBar::new(len))
         .with_style(
             ProgressStyle::with_template("[{bar:20.cyan/blue}] {msg} {pos}/{len}")
                 .unwrap()
@@ -0,0 +1,213 @@
+use crate::torrent;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often progress is written out during a long verification.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Identifies the contents of a source file well enough to tell whether earlier results still
+/// apply.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct FileIdentity {
+    source: PathBuf,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileIdentity {
+    fn new(source: &Path) -> Option<FileIdentity> {
+        let metadata = std::fs::metadata(source).ok()?;
+        Some(FileIdentity {
+            source: source.to_path_buf(),
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        })
+    }
+}
+
+/// The on-disk format of a checkpoint.
+#[derive(Debug, Deserialize, Serialize)]
+struct State {
+    /// Keyed by path in the torrent.
+    files: BTreeMap<PathBuf, FileIdentity>,
+    piece_count: usize,
+    /// A hex-encoded bitfield of verified pieces, most significant bit first.
+    verified: String,
+}
+
+struct Progress {
+    verified: Vec<u8>,
+    last_saved: Instant,
+}
+
+/// Records which pieces of a torrent have been verified against the current source files, so an
+/// interrupted full check can pick up where it left off. Progress is discarded if any source file
+/// has changed size or modification time since it was recorded.
+pub struct Checkpoint {
+    path: PathBuf,
+    files: BTreeMap<PathBuf, FileIdentity>,
+    piece_count: usize,
+    progress: Mutex<Progress>,
+}
+
+impl Checkpoint {
+    /// Loads the checkpoint for the torrent with `info_hash` from `dir`, or starts a new one if
+    /// there is none or it no longer applies. Returns `None` if the source files cannot be
+    /// identified.
+    pub fn load(
+        dir: &Path,
+        info_hash: &torrent::Digest,
+        info: &torrent::Info,
+        mapping: &HashMap<&Path, &Path>,
+    ) -> Option<Checkpoint> {
+        let files = mapping
+            .iter()
+            .map(|(path, source)| Some((path.to_path_buf(), FileIdentity::new(source)?)))
+            .collect::<Option<BTreeMap<_, _>>>()?;
+        let piece_count = info.pieces.len();
+        let path = dir.join(format!("{info_hash}.json"));
+        let verified = std::fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<State>(&bytes).ok())
+            .filter(|state| state.files == files && state.piece_count == piece_count)
+            .and_then(|state| decode_hex(&state.verified))
+            .filter(|verified| verified.len() == piece_count.div_ceil(8))
+            .unwrap_or_else(|| vec![0; piece_count.div_ceil(8)]);
+        Some(Checkpoint {
+            path,
+            files,
+            piece_count,
+            progress: Mutex::new(Progress {
+                verified,
+                last_saved: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn is_verified(&self, index: usize) -> bool {
+        is_set(&self.progress.lock().unwrap().verified, index)
+    }
+
+    /// Records that the piece at `index` matched, saving progress if it has not been saved
+    /// recently. A failed save is not retried until the next interval.
+    pub fn mark_verified(&self, index: usize) -> std::io::Result<()> {
+        let mut progress = self.progress.lock().unwrap();
+        progress.verified[index / 8] |= 0x80 >> (index % 8);
+        if progress.last_saved.elapsed() < SAVE_INTERVAL {
+            return Ok(());
+        }
+        progress.last_saved = Instant::now();
+        self.save(&progress.verified)
+    }
+
+    /// Saves progress, or removes the checkpoint if every piece has been verified.
+    pub fn finish(&self) -> std::io::Result<()> {
+        let progress = self.progress.lock().unwrap();
+        if (0..self.piece_count).all(|index| is_set(&progress.verified, index)) {
+            return match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            };
+        }
+        self.save(&progress.verified)
+    }
+
+    fn save(&self, verified: &[u8]) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Write to a temporary file first, so an interruption never leaves a truncated checkpoint.
+        let temp = self.path.with_extension("json.tmp");
+        std::fs::write(
+            &temp,
+            serde_json::to_vec(&State {
+                files: self.files.clone(),
+                piece_count: self.piece_count,
+                verified: encode_hex(verified),
+            })?,
+        )?;
+        std::fs::rename(&temp, &self.path)
+    }
+}
+
+/// The default directory for checkpoints: `$XDG_STATE_HOME/pollinators/checkpoints`.
+pub fn default_checkpoint_dir() -> Option<PathBuf> {
+    Some(crate::config::state_dir()?.join("checkpoints"))
+}
+
+fn is_set(bitfield: &[u8], index: usize) -> bool {
+    bitfield[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(encode_hex(&[0x00, 0xa5, 0xff]), "00a5ff");
+        assert_eq!(decode_hex("00a5ff"), Some(vec![0x00, 0xa5, 0xff]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+    }
+
+    #[test]
+    fn resumes_until_source_changes() {
+        let dir = crate::util::TempDir::new("checkpoint");
+        let source = dir.join("source");
+        std::fs::write(&source, b"0123456789").unwrap();
+        let info = torrent::Info {
+            files: vec![],
+            is_single_file: true,
+            name: "a".into(),
+            piece_length: 5,
+            pieces: vec![
+                torrent::Piece {
+                    hash: torrent::Digest::default(),
+                    file_slices: vec![],
+                };
+                2
+            ],
+            source: None,
+            private: false,
+        };
+        let mapping = HashMap::from([(Path::new("a"), source.as_path())]);
+        let hash = torrent::Digest::default();
+
+        let checkpoint = Checkpoint::load(&dir, &hash, &info, &mapping).unwrap();
+        checkpoint.mark_verified(1).unwrap();
+        checkpoint.finish().unwrap();
+
+        let checkpoint = Checkpoint::load(&dir, &hash, &info, &mapping).unwrap();
+        assert!(!checkpoint.is_verified(0));
+        assert!(checkpoint.is_verified(1));
+
+        std::fs::write(&source, b"01234567890").unwrap();
+        let checkpoint = Checkpoint::load(&dir, &hash, &info, &mapping).unwrap();
+        assert!(!checkpoint.is_verified(1));
+        checkpoint.mark_verified(0).unwrap();
+        checkpoint.mark_verified(1).unwrap();
+        checkpoint.finish().unwrap();
+        assert!(!dir.join(format!("{hash}.json")).exists());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+/// The kind of storage backing a device, which determines how many concurrent reads it can serve
+/// efficiently.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeviceKind {
+    /// A spinning disk, where concurrent reads cause seeks.
+    Rotational,
+    /// An SSD, or anything else that is not known to be rotational, e.g. network filesystems.
+    NonRotational,
+}
+
+/// Per-device limits on how many workers read concurrently.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimits {
+    /// Limit for spinning disks.
+    pub rotational: usize,
+    /// Limit for other devices; `None` lets every worker thread read concurrently.
+    pub non_rotational: Option<usize>,
+    /// Overrides keyed by a path on the device, e.g. a mount point.
+    pub devices: HashMap<PathBuf, usize>,
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> ConcurrencyLimits {
+        ConcurrencyLimits {
+            rotational: 1,
+            non_rotational: None,
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl ConcurrencyLimits {
+    /// Returns the concurrency limit for `dev`, or `None` if it is unlimited.
+    pub fn limit(&self, dev: u64) -> Option<usize> {
+        let limit = self
+            .devices
+            .iter()
+            .find(|(path, _)| device_of(path) == Some(dev))
+            .map(|(_, limit)| *limit);
+        if limit.is_some() {
+            return limit;
+        }
+        match device_kind(dev) {
+            DeviceKind::Rotational => Some(self.rotational),
+            DeviceKind::NonRotational => self.non_rotational,
+        }
+    }
+}
+
+/// Counts the workers reading from each device, across every verification sharing it, and makes
+/// workers wait once a device is at its concurrency limit.
+#[derive(Debug)]
+pub struct DeviceSlots {
+    limits: ConcurrencyLimits,
+    /// The limit for each device seen so far, and how many workers are reading from it.
+    devices: Mutex<HashMap<u64, (Option<usize>, usize)>>,
+    released: Condvar,
+}
+
+impl DeviceSlots {
+    pub fn new(limits: &ConcurrencyLimits) -> DeviceSlots {
+        DeviceSlots {
+            limits: limits.clone(),
+            devices: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Waits until `dev` is below its concurrency limit, then claims a slot on it until the
+    /// returned guard is dropped.
+    pub fn acquire(&self, dev: u64) -> DeviceSlot<'_> {
+        let mut devices = self.devices.lock().unwrap();
+        loop {
+            let (limit, readers) = devices
+                .entry(dev)
+                .or_insert_with(|| (self.limits.limit(dev), 0));
+            if limit.is_none_or(|limit| *readers < limit.max(1)) {
+                *readers += 1;
+                return DeviceSlot { slots: self, dev };
+            }
+            devices = self.released.wait(devices).unwrap();
+        }
+    }
+}
+
+/// A claim on one of a device's concurrency slots, released on drop.
+pub struct DeviceSlot<'a> {
+    slots: &'a DeviceSlots,
+    dev: u64,
+}
+
+impl Drop for DeviceSlot<'_> {
+    fn drop(&mut self) {
+        let mut devices = self.slots.devices.lock().unwrap();
+        if let Some((_, readers)) = devices.get_mut(&self.dev) {
+            *readers -= 1;
+        }
+        self.slots.released.notify_all();
+    }
+}
+
+pub fn device_of(path: &Path) -> Option<u64> {
+    std::fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+/// Splits a `st_dev` value into its major and minor numbers, using the Linux encoding.
+fn major_minor(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+    (major, minor)
+}
+
+/// Determines the kind of a block device from sysfs. Partitions do not have their own queue
+/// attributes, so this falls back to the parent device.
+pub fn device_kind(dev: u64) -> DeviceKind {
+    let (major, minor) = major_minor(dev);
+    let base = PathBuf::from(format!("/sys/dev/block/{major}:{minor}"));
+    [
+        base.join("queue/rotational"),
+        base.join("../queue/rotational"),
+    ]
+    .iter()
+    .find_map(|path| std::fs::read_to_string(path).ok())
+    .map_or(DeviceKind::NonRotational, |rotational| {
+        if rotational.trim() == "1" {
+            DeviceKind::Rotational
+        } else {
+            DeviceKind::NonRotational
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_wait_for_release() {
+        let dir = crate::util::TempDir::new("slots");
+        let dev = device_of(&dir).unwrap();
+        let slots = DeviceSlots::new(&ConcurrencyLimits {
+            devices: HashMap::from([(dir.to_path_buf(), 1)]),
+            ..Default::default()
+        });
+        let slot = slots.acquire(dev);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let _slot = slots.acquire(dev);
+                sender.send(()).unwrap();
+            });
+            let timeout = std::time::Duration::from_millis(50);
+            assert!(receiver.recv_timeout(timeout).is_err());
+            drop(slot);
+            receiver.recv().unwrap();
+        });
+    }
+
+    #[test]
+    fn major_minor_decoding() {
+        assert_eq!(major_minor(0x0801), (8, 1));
+        assert_eq!(major_minor(0x0803), (8, 3));
+        assert_eq!(major_minor(0x10300), (259, 0));
+        assert_eq!(
+            major_minor(0x0000_1000_0000_0000 | 0x0100_0000),
+            (0x1000, 0x1000)
+        );
+    }
+}
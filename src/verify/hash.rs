@@ -0,0 +1,94 @@
+use sha1::Digest as _;
+use std::sync::OnceLock;
+
+/// An implementation of SHA-1 used to hash pieces.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashBackend {
+    /// A portable implementation that runs anywhere.
+    Portable,
+    /// Uses the SHA extensions (SHA-NI) on x86 CPUs that support them.
+    Accelerated,
+}
+
+impl HashBackend {
+    /// The fastest backend supported by this CPU, detected once and then cached.
+    pub fn detect() -> HashBackend {
+        static DETECTED: OnceLock<HashBackend> = OnceLock::new();
+        *DETECTED.get_or_init(|| {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            if std::arch::is_x86_feature_detected!("sha")
+                && std::arch::is_x86_feature_detected!("sse2")
+                && std::arch::is_x86_feature_detected!("ssse3")
+                && std::arch::is_x86_feature_detected!("sse4.1")
+            {
+                return HashBackend::Accelerated;
+            }
+            HashBackend::Portable
+        })
+    }
+}
+
+/// A SHA-1 hasher dispatching to one of the available backends.
+pub enum Sha1 {
+    Portable(sha1_smol::Sha1),
+    Accelerated(sha1::Sha1),
+}
+
+impl Default for Sha1 {
+    fn default() -> Sha1 {
+        Sha1::with_backend(HashBackend::detect())
+    }
+}
+
+impl Sha1 {
+    /// Creates a hasher using the best backend for this CPU.
+    pub fn new() -> Sha1 {
+        Sha1::default()
+    }
+
+    pub fn with_backend(backend: HashBackend) -> Sha1 {
+        match backend {
+            HashBackend::Portable => Sha1::Portable(sha1_smol::Sha1::new()),
+            HashBackend::Accelerated => Sha1::Accelerated(sha1::Sha1::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Sha1::Portable(hasher) => hasher.update(data),
+            Sha1::Accelerated(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn digest(self) -> [u8; sha1_smol::DIGEST_LENGTH] {
+        match self {
+            Sha1::Portable(hasher) => hasher.digest().bytes(),
+            Sha1::Accelerated(hasher) => hasher.finalize().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_agree() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let digest = |backend| {
+            let mut hasher = Sha1::with_backend(backend);
+            for chunk in data.chunks(333) {
+                hasher.update(chunk);
+            }
+            hasher.digest()
+        };
+        assert_eq!(
+            digest(HashBackend::Portable),
+            digest(HashBackend::Accelerated)
+        );
+        assert_eq!(
+            Sha1::with_backend(HashBackend::Accelerated).digest(),
+            sha1_smol::Sha1::new().digest().bytes()
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use super::hash::Sha1;
+use super::reader::PieceReader;
+use super::RetryPolicy;
+use crate::error::Result;
+use crate::torrent;
+use rustix::mm::{Advice, MapFlags, ProtFlags};
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// How many source files a verification keeps mapped once no worker is reading from them.
+const MAX_MAPPED_FILES: usize = 64;
+
+/// A read-only mapping of an entire file.
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    /// Maps `path`. Fails for empty files, files too large for the address space, or any other
+    /// `mmap` failure.
+    fn open(path: &Path) -> std::io::Result<Mapping> {
+        let file = File::open(path)?;
+        let len = usize::try_from(file.metadata()?.len()).map_err(std::io::Error::other)?;
+        if len == 0 {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        // SAFETY: a fresh private, read-only mapping does not alias any Rust object. If the file is
+        // truncated while mapped, accessing the truncated range raises SIGBUS, as with any mmap.
+        let ptr = unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                len,
+                ProtFlags::READ,
+                MapFlags::PRIVATE,
+                &file,
+                0,
+            )?
+        };
+        // SAFETY: `ptr` and `len` describe the mapping created above. The advice is only a hint,
+        // so failure is not an error.
+        let _ = unsafe { rustix::mm::madvise(ptr, len, Advice::Sequential) };
+        Ok(Mapping { ptr, len })
+    }
+
+    fn get(&self, offset: u64, length: u64) -> Option<&[u8]> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(length).ok()?)?;
+        if end > self.len {
+            return None;
+        }
+        // SAFETY: the range is within the mapping, which lives as long as `self`.
+        Some(unsafe { std::slice::from_raw_parts(self.ptr.cast::<u8>().add(start), end - start) })
+    }
+}
+
+// SAFETY: the mapping is read-only and private, so it may be read from any thread, and it is only
+// unmapped once, on drop.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` and `len` describe a mapping owned by `self`, and no slices borrowed from
+        // it outlive `self`.
+        let _ = unsafe { rustix::mm::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Mapped source files, shared by every worker in a verification so that each file is mapped once
+/// rather than once per lane.
+#[derive(Default)]
+pub struct MappingCache {
+    /// `None` records a file that could not be mapped, so it is not retried for every piece.
+    maps: Mutex<HashMap<PathBuf, Option<Arc<Mapping>>>>,
+}
+
+impl MappingCache {
+    fn get(&self, path: &Path) -> Option<Arc<Mapping>> {
+        if let Some(map) = self.maps.lock().unwrap().get(path) {
+            return map.clone();
+        }
+        // Mapped without holding the lock. If two workers race, the loser's mapping is dropped.
+        let map = Mapping::open(path).ok().map(Arc::new);
+        let mut maps = self.maps.lock().unwrap();
+        if maps.len() >= MAX_MAPPED_FILES {
+            maps.retain(|_, map| map.as_ref().is_some_and(|map| Arc::strong_count(map) > 1));
+        }
+        maps.entry(path.to_path_buf()).or_insert(map).clone()
+    }
+}
+
+/// Hashes pieces directly from memory-mapped source files, avoiding a copy into a read buffer.
+/// Pieces that cannot be hashed this way (e.g. the file cannot be mapped, or is shorter than
+/// expected) are checked with `PieceReader` instead.
+pub struct MmapReader<'a> {
+    mapping: &'a HashMap<&'a Path, &'a Path>,
+    fallback: PieceReader<'a>,
+    maps: &'a MappingCache,
+}
+
+impl<'a> MmapReader<'a> {
+    pub fn new(
+        mapping: &'a HashMap<&'a Path, &'a Path>,
+        retry: &'a RetryPolicy,
+        maps: &'a MappingCache,
+    ) -> Self {
+        MmapReader {
+            mapping,
+            fallback: PieceReader::for_thread(mapping, retry),
+            maps,
+        }
+    }
+
+    /// Returns whether the piece's contents match its expected hash.
+    pub fn check(&mut self, piece: &torrent::Piece) -> Result<bool> {
+        match self.hash(piece) {
+            Some(matches) => Ok(matches),
+            None => self.fallback.check(piece),
+        }
+    }
+
+    fn hash(&mut self, piece: &torrent::Piece) -> Option<bool> {
+        let mut sha1 = Sha1::new();
+        for slice in &piece.file_slices {
+            if slice.length == 0 {
+                continue;
+            }
+            let path = *self.mapping.get::<Path>(slice.path.as_ref())?;
+            let map = self.maps.get(path)?;
+            sha1.update(map.get(slice.offset, slice.length)?);
+        }
+        Some(sha1.digest() == piece.hash.bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_bounds() {
+        let dir = crate::util::TempDir::new("mmap");
+        let path = dir.join("file");
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let map = Mapping::open(&path).unwrap();
+        assert_eq!(map.get(0, 5), Some(&b"hello"[..]));
+        assert_eq!(map.get(6, 5), Some(&b"world"[..]));
+        assert_eq!(map.get(6, 6), None);
+        assert_eq!(map.get(u64::MAX, 1), None);
+        drop(map);
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(Mapping::open(&path).is_err());
+    }
+
+    #[test]
+    fn checks_pieces_against_hashes() {
+        use crate::error::Error;
+        use crate::verify::testing::hashed_piece;
+
+        let dir = crate::util::TempDir::new("mmap");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std::fs::write(&a, b"hello ").unwrap();
+        std::fs::write(&b, b"world").unwrap();
+        let mapping = HashMap::from([(Path::new("a"), a.as_path()), (Path::new("b"), b.as_path())]);
+        let retry = RetryPolicy {
+            retries: 0,
+            ..RetryPolicy::default()
+        };
+        let maps = MappingCache::default();
+        let mut reader = MmapReader::new(&mapping, &retry, &maps);
+
+        let spanning = hashed_piece(b"hello world", &[("a", 0, 6), ("b", 0, 5)]);
+        assert!(reader.check(&spanning).unwrap());
+        let corrupted = hashed_piece(b"hello there", &[("a", 0, 6), ("b", 0, 5)]);
+        assert!(!reader.check(&corrupted).unwrap());
+
+        // Ranges past the end of the mapping fall back to reading, which reports the short read.
+        std::fs::write(&b, b"wor").unwrap();
+        let truncated = MappingCache::default();
+        let mut reader = MmapReader::new(&mapping, &retry, &truncated);
+        assert!(matches!(
+            reader.check(&spanning),
+            Err(Error::ShortRead { actual: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn mappings_shared() {
+        let dir = crate::util::TempDir::new("mmap");
+        let path = dir.join("file");
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let maps = MappingCache::default();
+        let map = maps.get(&path).unwrap();
+        assert!(Arc::ptr_eq(&map, &maps.get(&path).unwrap()));
+        assert!(maps.get(&dir.join("missing")).is_none());
+    }
+}
@@ -0,0 +1,644 @@
+use crate::error::{Error, Result};
+use crate::report::{FileCheck, HashCheckStats};
+use crate::torrent;
+use crate::util;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+mod checkpoint;
+mod device;
+mod hash;
+mod mmap;
+mod reader;
+mod sample;
+mod shared;
+mod throttle;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use checkpoint::{default_checkpoint_dir, Checkpoint};
+pub use device::{device_kind, device_of, ConcurrencyLimits, DeviceKind, DeviceSlots};
+pub use hash::{HashBackend, Sha1};
+pub use mmap::{MappingCache, MmapReader};
+pub use reader::PieceReader;
+pub use sample::{select_pieces, SampleOptions, Selection};
+pub use shared::{verify_shared, PieceCache, SharedCheck};
+pub use throttle::{IoPriority, RateLimits, Throttle};
+
+/// The smallest run of consecutive pieces handed to a single worker, so that reads stay mostly
+/// sequential within each file.
+const MIN_PIECES_PER_TASK: usize = 8;
+
+/// How source files are read during verification.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadMode {
+    /// One `pread` per slice.
+    #[default]
+    Pread,
+    /// Hash directly from memory-mapped files, falling back to `pread` for files that cannot be
+    /// mapped.
+    Mmap,
+    /// Batched reads through io_uring, falling back to `pread` if io_uring is unavailable.
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    IoUring,
+}
+
+/// How thoroughly matched source files are checked before cross-seeding.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerifyMode {
+    /// Do not hash check anything; the matches are trusted based on file sizes alone.
+    None,
+    /// Check a sample of pieces from each file.
+    Sample,
+    /// Check every piece.
+    Full,
+}
+
+/// Options controlling how source files are read during verification.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    pub read_mode: ReadMode,
+    pub retry: RetryPolicy,
+    pub concurrency: ConcurrencyLimits,
+    pub rate_limits: RateLimits,
+    pub io_priority: IoPriority,
+    /// Shared by verifications that run concurrently, so that together they stay within
+    /// `concurrency` and `rate_limits`. If unset, each verification is limited on its own.
+    pub limiter: Option<Arc<Limiter>>,
+    /// Where to draw progress and report problems saving checkpoints.
+    pub output: util::Output,
+}
+
+/// The read rate and device concurrency limits, as state that can be shared between
+/// verifications.
+#[derive(Debug)]
+pub struct Limiter {
+    throttle: Throttle,
+    slots: DeviceSlots,
+}
+
+impl Limiter {
+    pub fn new(options: &VerifyOptions) -> Limiter {
+        Limiter {
+            throttle: Throttle::new(&options.rate_limits),
+            slots: DeviceSlots::new(&options.concurrency),
+        }
+    }
+}
+
+/// How to handle transient errors when reading source files.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How many times to retry a read after a transient error.
+    pub retries: u32,
+    /// How long to wait between attempts.
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 2,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Calls `f` until it succeeds, fails with a non-transient error, or retries are exhausted.
+    fn run<T>(&self, mut f: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(err) if attempt < self.retries && is_transient(&err) => {
+                    attempt += 1;
+                    std::thread::sleep(self.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether an I/O error might succeed on retry, e.g. EIO from a flaky disk, an NFS timeout, or a
+/// stale NFS handle, which readers recover from by reopening the file.
+fn is_transient(err: &std::io::Error) -> bool {
+    use rustix::io::Errno;
+    matches!(
+        Errno::from_io_error(err),
+        Some(Errno::IO | Errno::TIMEDOUT | Errno::AGAIN | Errno::INTR | Errno::STALE)
+    )
+}
+
+pub trait CheckWithFileMapping {
+    fn check(&self, mapping: &HashMap<&Path, &Path>, retry: &RetryPolicy) -> Result<bool>;
+}
+
+impl CheckWithFileMapping for torrent::Piece {
+    fn check(&self, mapping: &HashMap<&Path, &Path>, retry: &RetryPolicy) -> Result<bool> {
+        PieceReader::new(mapping, retry).check(self)
+    }
+}
+
+/// The outcome of checking a set of pieces against their mapped source files.
+pub struct Verification<'a> {
+    pub stats: HashCheckStats,
+    /// Pieces that were read successfully but did not match the expected hash.
+    pub failed_pieces: Vec<&'a torrent::Piece>,
+    /// Pieces that could not be read, along with the error encountered.
+    pub errored_pieces: Vec<(&'a torrent::Piece, Error)>,
+    /// Results for each file touched by a checked piece, in torrent order.
+    pub files: Vec<FileCheck>,
+}
+
+impl Verification<'_> {
+    /// Converts failed pieces into an error. Hash mismatches take precedence over read errors,
+    /// since retrying cannot fix them.
+    pub fn into_result(self) -> Result<()> {
+        if self.failed_pieces.is_empty() {
+            if self.errored_pieces.is_empty() {
+                return Ok(());
+            }
+            let mut failures = BTreeMap::<PathBuf, (usize, String)>::new();
+            for (_piece, err) in &self.errored_pieces {
+                let path = match err {
+                    Error::Read { path, .. } | Error::ShortRead { path, .. } => path.clone(),
+                    Error::NoMapping(path) => path.clone(),
+                    _ => PathBuf::new(),
+                };
+                failures
+                    .entry(path)
+                    .or_insert_with(|| (0, err.to_string()))
+                    .0 += 1;
+            }
+            return Err(Error::ReadFailures {
+                failures: failures
+                    .into_iter()
+                    .map(|(path, (count, error))| (path, format!("{count} pieces; first: {error}")))
+                    .collect(),
+            });
+        }
+        Err(Error::HashMismatch {
+            files: self
+                .files
+                .into_iter()
+                .filter(|file| file.pieces_failed > 0)
+                .collect(),
+        })
+    }
+}
+
+/// Hash checks the selected pieces in parallel, using `mapping` to find the source file for each
+/// path in the torrent. For full checks, pieces already verified in `checkpoint` are skipped and
+/// newly verified pieces are recorded in it. Pieces already checked by `verify_shared`, with their
+/// results in `cache`, are not read again.
+pub fn verify<'a>(
+    info: &torrent::Info,
+    selection: &'a Selection,
+    mapping: &HashMap<&Path, &Path>,
+    checkpoint: Option<&Checkpoint>,
+    cache: Option<&PieceCache>,
+    options: &VerifyOptions,
+) -> Verification<'a> {
+    let pieces = &selection.pieces;
+    let checkpoint = checkpoint.filter(|_| selection.full);
+    // Checkpoints are indexed by position in the torrent, which for a full check is the same as
+    // the position in `pieces`. Pieces are identified by address, since identical pieces may
+    // appear more than once.
+    let indices: HashMap<usize, usize> = match checkpoint {
+        Some(_) => pieces
+            .iter()
+            .enumerate()
+            .map(|(index, piece)| (std::ptr::from_ref(piece) as usize, index))
+            .collect(),
+        None => HashMap::new(),
+    };
+    let mark_verified = |piece: &torrent::Piece| {
+        if let Some(checkpoint) = checkpoint {
+            let index = indices[&(std::ptr::from_ref(piece) as usize)];
+            if let Err(err) = checkpoint.mark_verified(index) {
+                crate::status!(options.output, "unable to save checkpoint: {err}");
+            }
+        }
+    };
+    let remaining: Vec<_> = pieces
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !checkpoint.is_some_and(|checkpoint| checkpoint.is_verified(*index)))
+        .map(|(_, piece)| piece)
+        .collect();
+    let pieces_resumed = pieces.len() - remaining.len();
+
+    // Reuse results for pieces already checked as part of another torrent.
+    let mut results = vec![];
+    let mut unchecked = vec![];
+    match cache.filter(|cache| !cache.is_empty()) {
+        Some(cache) => {
+            for piece in remaining {
+                match cache.get(piece) {
+                    Some(true) => mark_verified(piece),
+                    Some(false) => results.push((piece, None)),
+                    None => unchecked.push(piece),
+                }
+            }
+        }
+        None => unchecked = remaining,
+    }
+    let pieces_shared = pieces.len() - pieces_resumed - unchecked.len();
+
+    let bar = util::new_bar(options.output, pieces.len() as u64)
+        .with_message(format!("hashing {}...", info.name));
+    bar.inc((pieces.len() - unchecked.len()) as u64);
+    let start = std::time::Instant::now();
+    results.extend(check_pieces(
+        &unchecked,
+        mapping,
+        options,
+        &bar,
+        |piece, result| {
+            if let Ok(true) = result {
+                mark_verified(piece);
+            }
+        },
+    ));
+    let mut failed_pieces = vec![];
+    let mut errored_pieces = vec![];
+    for (piece, err) in results {
+        match err {
+            None => failed_pieces.push(piece),
+            Some(err) => errored_pieces.push((piece, err)),
+        }
+    }
+    if let Some(checkpoint) = checkpoint {
+        if let Err(err) = checkpoint.finish() {
+            crate::status!(options.output, "unable to save checkpoint: {err}");
+        }
+    }
+    let stats = HashCheckStats {
+        full: selection.full,
+        seed: selection.seed,
+        pieces_total: info.pieces.len(),
+        pieces_checked: pieces.len(),
+        pieces_resumed,
+        pieces_shared,
+        pieces_failed: failed_pieces.len(),
+        pieces_errored: errored_pieces.len(),
+        bytes_checked: pieces.iter().map(piece_length).sum(),
+        elapsed_ms: start.elapsed().as_millis(),
+    };
+    let files = file_checks(
+        pieces,
+        &failed_pieces,
+        errored_pieces.iter().map(|(piece, _)| *piece),
+        mapping,
+    );
+    Verification {
+        stats,
+        failed_pieces,
+        errored_pieces,
+        files,
+    }
+}
+
+/// Summarizes the results of checking `pieces` by file.
+fn file_checks<'a>(
+    pieces: &[torrent::Piece],
+    failed: &[&torrent::Piece],
+    errored: impl Iterator<Item = &'a torrent::Piece>,
+    mapping: &HashMap<&Path, &Path>,
+) -> Vec<FileCheck> {
+    let failed: HashSet<_> = failed.iter().map(|piece| *piece as *const _).collect();
+    let errored: HashSet<_> = errored.map(|piece| piece as *const _).collect();
+    let mut files: Vec<FileCheck> = vec![];
+    for piece in pieces {
+        let piece_ptr = piece as *const _;
+        let is_failed = failed.contains(&piece_ptr);
+        let is_errored = errored.contains(&piece_ptr);
+        for slice in &piece.file_slices {
+            let file = match files.last_mut() {
+                Some(file) if file.path == slice.path => file,
+                _ => {
+                    files.push(FileCheck {
+                        path: slice.path.clone(),
+                        source: mapping
+                            .get::<Path>(slice.path.as_ref())
+                            .map(|path| path.to_path_buf()),
+                        pieces_checked: 0,
+                        pieces_failed: 0,
+                        pieces_errored: 0,
+                        bad_range: None,
+                        bytes_checked: 0,
+                        bytes_matched: 0,
+                    });
+                    files.last_mut().unwrap()
+                }
+            };
+            file.pieces_checked += 1;
+            file.bytes_checked += slice.length;
+            if is_failed {
+                file.pieces_failed += 1;
+                let end = slice.offset + slice.length;
+                file.bad_range = Some(match file.bad_range {
+                    Some((start, _)) => (start, end),
+                    None => (slice.offset, end),
+                });
+            } else if is_errored {
+                file.pieces_errored += 1;
+            } else {
+                file.bytes_matched += slice.length;
+            }
+        }
+    }
+    files
+}
+
+/// Checks `pieces` in parallel, calling `on_result` with the outcome of each one, and returns the
+/// pieces that did not match or could not be read.
+fn check_pieces<'a>(
+    pieces: &[&'a torrent::Piece],
+    mapping: &HashMap<&Path, &Path>,
+    options: &VerifyOptions,
+    bar: &indicatif::ProgressBar,
+    on_result: impl Fn(&'a torrent::Piece, &Result<bool>) + Sync,
+) -> Vec<(&'a torrent::Piece, Option<Error>)> {
+    let maps = MappingCache::default();
+    let lanes = schedule(pieces, mapping, &options.concurrency);
+    with_limiter(options, |limiter| {
+        lanes
+            .into_par_iter()
+            .flat_map_iter(|(dev, lane)| {
+                options.io_priority.apply();
+                // Held for the whole lane, so that its reads stay sequential.
+                let _slot = dev.map(|dev| limiter.slots.acquire(dev));
+                let mut results = vec![];
+                check_lane(&lane, mapping, options, &maps, &mut |piece, result| {
+                    limiter.throttle.consume(dev, piece_length(piece));
+                    on_result(piece, &result);
+                    match result {
+                        Ok(true) => (),
+                        Ok(false) => results.push((piece, None)),
+                        Err(err) => results.push((piece, Some(err))),
+                    }
+                    bar.inc(1);
+                });
+                results
+            })
+            .collect()
+    })
+}
+
+/// Calls `f` with the limiter shared through `options`, or one of its own if there is none.
+fn with_limiter<T>(options: &VerifyOptions, f: impl FnOnce(&Limiter) -> T) -> T {
+    match &options.limiter {
+        Some(limiter) => f(limiter),
+        None => f(&Limiter::new(options)),
+    }
+}
+
+fn piece_length(piece: &torrent::Piece) -> u64 {
+    piece.file_slices.iter().map(|slice| slice.length).sum()
+}
+
+/// Checks each piece in `lane` in order, passing the results to `on_result`. In mmap mode, files
+/// are mapped through `maps`, which is shared by every lane.
+fn check_lane<'a>(
+    lane: &[&'a torrent::Piece],
+    mapping: &HashMap<&Path, &Path>,
+    options: &VerifyOptions,
+    maps: &MappingCache,
+    on_result: &mut impl FnMut(&'a torrent::Piece, Result<bool>),
+) {
+    match options.read_mode {
+        ReadMode::Pread => (),
+        ReadMode::Mmap => {
+            let mut reader = MmapReader::new(mapping, &options.retry, maps);
+            for piece in lane {
+                on_result(piece, reader.check(piece));
+            }
+            return;
+        }
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        ReadMode::IoUring => {
+            if uring::check_lane(lane, mapping, &options.retry, on_result) {
+                return;
+            }
+        }
+    }
+    let mut reader = PieceReader::for_thread(mapping, &options.retry);
+    for piece in lane {
+        on_result(piece, reader.check(piece));
+    }
+}
+
+/// Splits `pieces` into lanes, each of which is read sequentially by a single worker. Pieces are
+/// grouped by the device backing their first slice, and each device gets at most as many lanes as
+/// its concurrency limit, so spinning disks are not thrashed by concurrent seeks while separate
+/// disks and SSDs are still read in parallel. Each lane is returned with its device, if known.
+fn schedule<'a>(
+    pieces: &[&'a torrent::Piece],
+    mapping: &HashMap<&Path, &Path>,
+    limits: &ConcurrencyLimits,
+) -> Vec<(Option<u64>, Vec<&'a torrent::Piece>)> {
+    let mut devices = HashMap::<&Path, Option<u64>>::new();
+    let mut groups = HashMap::<Option<u64>, Vec<_>>::new();
+    for &piece in pieces {
+        let dev = piece
+            .file_slices
+            .first()
+            .and_then(|slice| mapping.get::<Path>(slice.path.as_ref()))
+            .and_then(|path| *devices.entry(path).or_insert_with(|| device_of(path)));
+        groups.entry(dev).or_default().push(piece);
+    }
+    groups
+        .into_iter()
+        .flat_map(|(dev, pieces)| {
+            let lanes = match dev.and_then(|dev| limits.limit(dev)) {
+                Some(limit) => limit,
+                None => pieces.len().div_ceil(MIN_PIECES_PER_TASK),
+            };
+            split_lanes(pieces, lanes)
+                .into_iter()
+                .map(move |lane| (dev, lane))
+        })
+        .collect()
+}
+
+/// Splits `items` into at most `lanes` contiguous runs of roughly equal length.
+fn split_lanes<T>(items: Vec<T>, lanes: usize) -> Vec<Vec<T>> {
+    let lane_len = items.len().div_ceil(lanes.max(1)).max(1);
+    let mut items = items.into_iter().peekable();
+    let mut result = vec![];
+    while items.peek().is_some() {
+        result.push(items.by_ref().take(lane_len).collect());
+    }
+    result
+}
+
+/// Helpers for tests that hash real files.
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    /// A piece covering `slices`, given as (path, offset, length), whose expected contents are
+    /// `contents`.
+    pub fn hashed_piece(contents: &[u8], slices: &[(&str, u64, u64)]) -> torrent::Piece {
+        let mut sha1 = Sha1::new();
+        sha1.update(contents);
+        torrent::Piece {
+            hash: sha1.digest().into(),
+            file_slices: slices
+                .iter()
+                .map(|&(path, offset, length)| torrent::FileSlice {
+                    path: path.into(),
+                    offset,
+                    length,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            delay: Duration::ZERO,
+        }
+    }
+
+    fn piece(slices: &[(&str, u64, u64)]) -> torrent::Piece {
+        torrent::Piece {
+            hash: torrent::Digest::default(),
+            file_slices: slices
+                .iter()
+                .map(|&(path, offset, length)| torrent::FileSlice {
+                    path: path.into(),
+                    offset,
+                    length,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn file_checks_by_file() {
+        let pieces = [
+            piece(&[("a", 0, 10)]),
+            piece(&[("a", 10, 10)]),
+            piece(&[("a", 20, 5), ("b", 0, 5)]),
+            piece(&[("b", 5, 10)]),
+            piece(&[("b", 15, 10)]),
+        ];
+        let mapping = HashMap::from([(Path::new("a"), Path::new("/src/a"))]);
+        let files = file_checks(
+            &pieces,
+            &[&pieces[1], &pieces[2]],
+            std::iter::once(&pieces[4]),
+            &mapping,
+        );
+        assert_eq!(files.len(), 2);
+
+        let (a, b) = (&files[0], &files[1]);
+        assert_eq!(a.source, Some(PathBuf::from("/src/a")));
+        assert_eq!((a.pieces_checked, a.pieces_failed), (3, 2));
+        assert_eq!(a.bad_range, Some((10, 25)));
+        assert_eq!(a.percent_matching(), 40.0);
+
+        assert_eq!(b.source, None);
+        assert_eq!(
+            (b.pieces_checked, b.pieces_failed, b.pieces_errored),
+            (3, 1, 1)
+        );
+        assert_eq!(b.bad_range, Some((0, 5)));
+        assert_eq!(b.percent_matching(), 40.0);
+    }
+
+    #[test]
+    fn limits_shared_between_verifications() {
+        let dir = crate::util::TempDir::new("limiter");
+        let source = dir.join("a");
+        std::fs::write(&source, [0; 200]).unwrap();
+        let mapping = HashMap::from([(Path::new("a"), source.as_path())]);
+        let pieces = [piece(&[("a", 0, 100)]), piece(&[("a", 100, 100)])];
+        let pieces: Vec<_> = pieces.iter().collect();
+        let mut options = VerifyOptions {
+            rate_limits: RateLimits {
+                global: Some(1000),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        options.limiter = Some(Arc::new(Limiter::new(&options)));
+
+        // Each verification reads 200 bytes, which alone would take 200ms at 1000 bytes/s.
+        let start = std::time::Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let bar = indicatif::ProgressBar::hidden();
+                    check_pieces(&pieces, &mapping, &options, &bar, |_, _| ());
+                });
+            }
+        });
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn split_lanes_contiguous() {
+        assert_eq!(split_lanes(Vec::<u32>::new(), 2), Vec::<Vec<u32>>::new());
+        assert_eq!(split_lanes(vec![1, 2, 3], 1), vec![vec![1, 2, 3]]);
+        assert_eq!(split_lanes(vec![1, 2, 3], 0), vec![vec![1, 2, 3]]);
+        assert_eq!(
+            split_lanes(vec![1, 2, 3, 4, 5], 2),
+            vec![vec![1, 2, 3], vec![4, 5]]
+        );
+        assert_eq!(split_lanes(vec![1, 2], 4), vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let attempts = Cell::new(0);
+        let result = policy(2).run(|| {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 => Err(rustix::io::Errno::IO.into()),
+                2 => Err(rustix::io::Errno::STALE.into()),
+                _ => Ok(()),
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let attempts = Cell::new(0);
+        let result: std::io::Result<()> = policy(2).run(|| {
+            attempts.set(attempts.get() + 1);
+            Err(rustix::io::Errno::TIMEDOUT.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+    }
+
+    #[test]
+    fn no_retry_for_permanent_errors() {
+        let attempts = Cell::new(0);
+        let result: std::io::Result<()> = policy(2).run(|| {
+            attempts.set(attempts.get() + 1);
+            Err(std::io::ErrorKind::NotFound.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+}
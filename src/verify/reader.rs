@@ -0,0 +1,212 @@
+use super::hash::Sha1;
+use super::RetryPolicy;
+use crate::error::{Error, Result};
+use crate::torrent;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// How many source files each reader keeps open at once.
+const MAX_OPEN_FILES: usize = 16;
+
+/// A small LRU cache of open file handles, keyed by path.
+struct FileCache {
+    // Most recently used entries are at the front.
+    files: VecDeque<(PathBuf, File)>,
+    capacity: usize,
+}
+
+impl FileCache {
+    fn new(capacity: usize) -> FileCache {
+        FileCache {
+            files: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn get(&mut self, path: &Path) -> std::io::Result<&File> {
+        match self.files.iter().position(|(p, _)| p == path) {
+            Some(index) => {
+                let entry = self.files.remove(index).unwrap();
+                self.files.push_front(entry);
+            }
+            None => {
+                let file = File::open(path)?;
+                if self.files.len() == self.capacity {
+                    self.files.pop_back();
+                }
+                self.files.push_front((path.to_path_buf(), file));
+            }
+        }
+        Ok(&self.files.front().unwrap().1)
+    }
+
+    /// Drops the handle for `path`, so the next access reopens it. Used after a read error, since
+    /// some errors (e.g. a stale NFS handle) are tied to the open file rather than the path.
+    fn evict(&mut self, path: &Path) {
+        self.files.retain(|(p, _)| p != path);
+    }
+}
+
+/// The parts of a reader worth keeping between pieces: the read buffer and open file handles.
+struct ReaderState {
+    buffer: Vec<u8>,
+    files: FileCache,
+    /// The address of the mapping `files` were opened for.
+    mapping: usize,
+}
+
+impl Default for ReaderState {
+    fn default() -> ReaderState {
+        ReaderState {
+            buffer: vec![],
+            files: FileCache::new(MAX_OPEN_FILES),
+            mapping: 0,
+        }
+    }
+}
+
+thread_local! {
+    /// State left behind by the last reader created with `PieceReader::for_thread` on this
+    /// thread, so that each worker keeps its buffer and open files across the lanes it checks.
+    static STATE: RefCell<Option<ReaderState>> = const { RefCell::new(None) };
+}
+
+/// Reads and hashes pieces from their mapped source files. The read buffer and open file handles
+/// are reused across pieces, so pieces should be fed in file order to benefit from the handle
+/// cache and kernel readahead.
+pub struct PieceReader<'a> {
+    mapping: &'a HashMap<&'a Path, &'a Path>,
+    retry: &'a RetryPolicy,
+    state: ReaderState,
+    /// Whether `state` is returned to the thread when the reader is dropped.
+    per_thread: bool,
+}
+
+impl<'a> PieceReader<'a> {
+    pub fn new(mapping: &'a HashMap<&'a Path, &'a Path>, retry: &'a RetryPolicy) -> Self {
+        PieceReader {
+            mapping,
+            retry,
+            state: ReaderState::default(),
+            per_thread: false,
+        }
+    }
+
+    /// Like `new`, but picks up the buffer of the last such reader dropped on the calling thread,
+    /// and its open files if it was reading through the same `mapping`, i.e. for the same
+    /// verification. Workers create one of these per lane, which may be only a few pieces long.
+    pub fn for_thread(mapping: &'a HashMap<&'a Path, &'a Path>, retry: &'a RetryPolicy) -> Self {
+        let mut state: ReaderState = STATE.with_borrow_mut(Option::take).unwrap_or_default();
+        let address = std::ptr::from_ref(mapping) as usize;
+        if state.mapping != address {
+            // Files opened for an earlier verification may have been replaced since.
+            state.files = FileCache::new(MAX_OPEN_FILES);
+            state.mapping = address;
+        }
+        PieceReader {
+            mapping,
+            retry,
+            state,
+            per_thread: true,
+        }
+    }
+
+    /// Returns whether the piece's contents match its expected hash.
+    pub fn check(&mut self, piece: &torrent::Piece) -> Result<bool> {
+        let mut sha1 = Sha1::new();
+        for slice in &piece.file_slices {
+            let path = self
+                .mapping
+                .get::<Path>(slice.path.as_ref())
+                .ok_or_else(|| Error::NoMapping(slice.path.clone()))?;
+            let read_error = |source| Error::Read {
+                path: path.to_path_buf(),
+                source,
+            };
+            let length = usize::try_from(slice.length)
+                .map_err(|err| read_error(std::io::Error::other(err)))?;
+            let ReaderState { buffer, files, .. } = &mut self.state;
+            buffer.resize(length, 0);
+            let bytes_read = self
+                .retry
+                .run(|| {
+                    let result = files
+                        .get(path)
+                        .and_then(|file| Ok(rustix::io::pread(file, &mut *buffer, slice.offset)?));
+                    if result.is_err() {
+                        files.evict(path);
+                    }
+                    result
+                })
+                .map_err(read_error)?;
+            if bytes_read as u64 != slice.length {
+                return Err(Error::ShortRead {
+                    path: path.to_path_buf(),
+                    offset: slice.offset,
+                    expected: slice.length,
+                    actual: bytes_read as u64,
+                });
+            }
+            sha1.update(buffer);
+        }
+        Ok(sha1.digest() == piece.hash.bytes())
+    }
+}
+
+impl Drop for PieceReader<'_> {
+    fn drop(&mut self) {
+        if self.per_thread {
+            let state = std::mem::take(&mut self.state);
+            STATE.with_borrow_mut(|slot| *slot = Some(state));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_cache_evicts_least_recently_used() {
+        let dir = crate::util::TempDir::new("reader");
+        let paths: Vec<_> = (0..3).map(|i| dir.join(i.to_string())).collect();
+        for path in &paths {
+            std::fs::write(path, b"").unwrap();
+        }
+
+        let mut cache = FileCache::new(2);
+        cache.get(&paths[0]).unwrap();
+        cache.get(&paths[1]).unwrap();
+        cache.get(&paths[0]).unwrap();
+        cache.get(&paths[2]).unwrap();
+        let cached: Vec<_> = cache.files.iter().map(|(path, _)| path.clone()).collect();
+        assert_eq!(cached, vec![paths[2].clone(), paths[0].clone()]);
+
+        cache.evict(&paths[2]);
+        assert_eq!(cache.files.len(), 1);
+    }
+
+    #[test]
+    fn thread_reader_keeps_files_for_same_mapping() {
+        let dir = crate::util::TempDir::new("reader");
+        let source = dir.join("a");
+        std::fs::write(&source, b"0123456789").unwrap();
+        let mapping = HashMap::from([(Path::new("a"), source.as_path())]);
+        let retry = RetryPolicy::default();
+        let piece = crate::verify::testing::hashed_piece(b"01234", &[("a", 0, 5)]);
+
+        let mut reader = PieceReader::for_thread(&mapping, &retry);
+        assert!(reader.check(&piece).unwrap());
+        drop(reader);
+        let reader = PieceReader::for_thread(&mapping, &retry);
+        assert_eq!(reader.state.files.files.len(), 1);
+        assert!(reader.state.buffer.capacity() >= 5);
+        drop(reader);
+
+        let other = mapping.clone();
+        let reader = PieceReader::for_thread(&other, &retry);
+        assert!(reader.state.files.files.is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+use crate::torrent;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Options controlling which pieces a sampled check verifies.
+#[derive(Clone, Debug, Default)]
+pub struct SampleOptions {
+    /// How many pieces to test per file, on average: the budget is this times the number of files,
+    /// spread across files by size. Ignored if `coverage` is set.
+    pub pieces_per_file: usize,
+    /// The fraction of pieces to test, between 0 and 1.
+    pub coverage: Option<f64>,
+    /// Seed for choosing pieces; a random seed is used if unset.
+    pub seed: Option<u64>,
+}
+
+/// The pieces chosen for a hash check.
+pub struct Selection<'a> {
+    /// The selected pieces, in torrent order.
+    pub pieces: Cow<'a, [torrent::Piece]>,
+    /// Whether every piece was selected.
+    pub full: bool,
+    /// The seed used to sample pieces, which can be passed back in to repeat the same check.
+    pub seed: Option<u64>,
+}
+
+/// The range of pieces covering a single file.
+struct FilePieces<'a> {
+    path: &'a Path,
+    length: u64,
+    first: usize,
+    last: usize,
+}
+
+/// Groups pieces by file, in torrent order. Pieces spanning a file boundary belong to both files.
+fn file_pieces(pieces: &[torrent::Piece]) -> Vec<FilePieces<'_>> {
+    let mut files: Vec<FilePieces> = vec![];
+    for (index, piece) in pieces.iter().enumerate() {
+        for slice in &piece.file_slices {
+            match files.last_mut() {
+                Some(file) if file.path == slice.path => {
+                    file.length += slice.length;
+                    file.last = index;
+                }
+                _ => files.push(FilePieces {
+                    path: &slice.path,
+                    length: slice.length,
+                    first: index,
+                    last: index,
+                }),
+            }
+        }
+    }
+    files
+}
+
+/// Chooses which pieces to check. A full check verifies every piece. Otherwise, the first and last
+/// piece of every file are always checked: they catch most mislabeled or truncated content, and
+/// pieces spanning a file boundary validate two files at once. The remaining budget, set by either
+/// `coverage` or `pieces_per_file`, is spread across files in proportion to their size.
+pub fn select_pieces<'a>(
+    pieces: &'a [torrent::Piece],
+    full: bool,
+    options: &SampleOptions,
+) -> Selection<'a> {
+    if full {
+        // The hash checks are parallelized and can run faster than hash checks in many common
+        // torrent clients.
+        return Selection {
+            pieces: Cow::Borrowed(pieces),
+            full: true,
+            seed: None,
+        };
+    }
+    let seed = options.seed.unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let files = file_pieces(pieces);
+
+    let mut selected = BTreeSet::new();
+    for file in &files {
+        selected.insert(file.first);
+        selected.insert(file.last);
+    }
+
+    let budget = match options.coverage {
+        Some(coverage) => (coverage.clamp(0.0, 1.0) * pieces.len() as f64).ceil() as usize,
+        None => options.pieces_per_file.saturating_mul(files.len()),
+    };
+    let extra = budget.saturating_sub(selected.len()) as u128;
+    let total_length = files
+        .iter()
+        .map(|file| u128::from(file.length))
+        .sum::<u128>();
+    if extra > 0 && total_length > 0 {
+        for file in &files {
+            let quota = (extra * u128::from(file.length) + total_length / 2) / total_length;
+            let mut candidates: Vec<_> = (file.first..=file.last)
+                .filter(|index| !selected.contains(index))
+                .collect();
+            candidates.shuffle(&mut rng);
+            candidates.truncate(usize::try_from(quota).unwrap_or(usize::MAX));
+            selected.extend(candidates);
+        }
+    }
+
+    // Keep the selected pieces in torrent order, which is also file order.
+    Selection {
+        pieces: Cow::Owned(
+            selected
+                .into_iter()
+                .map(|index| pieces[index].clone())
+                .collect(),
+        ),
+        full: false,
+        seed: Some(seed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds pieces of `piece_length` bytes covering files with the given lengths.
+    fn pieces(piece_length: u64, file_lengths: &[u64]) -> Vec<torrent::Piece> {
+        let mut pieces = vec![];
+        let mut slices = vec![];
+        let mut piece_remaining = piece_length;
+        for (index, &length) in file_lengths.iter().enumerate() {
+            let mut offset = 0;
+            while offset < length {
+                let next = std::cmp::min(length - offset, piece_remaining);
+                slices.push(torrent::FileSlice {
+                    path: index.to_string().into(),
+                    offset,
+                    length: next,
+                });
+                offset += next;
+                piece_remaining -= next;
+                if piece_remaining == 0 {
+                    pieces.push(torrent::Piece {
+                        hash: torrent::Digest::default(),
+                        file_slices: std::mem::take(&mut slices),
+                    });
+                    piece_remaining = piece_length;
+                }
+            }
+        }
+        if !slices.is_empty() {
+            pieces.push(torrent::Piece {
+                hash: torrent::Digest::default(),
+                file_slices: slices,
+            });
+        }
+        pieces
+    }
+
+    fn indices(all: &[torrent::Piece], selection: &Selection) -> Vec<usize> {
+        selection
+            .pieces
+            .iter()
+            .map(|piece| all.iter().position(|p| p == piece).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn includes_first_last_and_boundary_pieces() {
+        // Files cover pieces 0-3, 3-5 and 5-9.
+        let all = pieces(10, &[35, 20, 45]);
+        let options = SampleOptions {
+            pieces_per_file: 0,
+            coverage: None,
+            seed: Some(1),
+        };
+        let selection = select_pieces(&all, false, &options);
+        assert_eq!(indices(&all, &selection), vec![0, 3, 5, 9]);
+        assert_eq!(selection.seed, Some(1));
+    }
+
+    #[test]
+    fn coverage_weighted_by_size() {
+        let all = pieces(10, &[10, 990]);
+        let options = SampleOptions {
+            pieces_per_file: 0,
+            coverage: Some(0.5),
+            seed: Some(7),
+        };
+        let selection = select_pieces(&all, false, &options);
+        let selected = indices(&all, &selection);
+        assert!(selected.len() >= 50);
+        assert!(selected.starts_with(&[0, 1]));
+        assert_eq!(selected.last(), Some(&99));
+    }
+
+    #[test]
+    fn seed_is_reproducible() {
+        let all = pieces(10, &[500, 500]);
+        let options = SampleOptions {
+            pieces_per_file: 5,
+            coverage: None,
+            seed: Some(42),
+        };
+        let first = indices(&all, &select_pieces(&all, false, &options));
+        let second = indices(&all, &select_pieces(&all, false, &options));
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 10);
+    }
+
+    #[test]
+    fn full_selects_everything() {
+        let all = pieces(10, &[25]);
+        let selection = select_pieces(&all, true, &SampleOptions::default());
+        assert!(selection.full);
+        assert_eq!(selection.pieces.len(), all.len());
+        assert_eq!(selection.seed, None);
+    }
+}
@@ -0,0 +1,455 @@
+use super::hash::Sha1;
+use super::{device_of, split_lanes, with_limiter, Checkpoint, Selection, VerifyOptions};
+use crate::torrent;
+use crate::util;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// How much of a source file a single task in the shared pass reads, so that large files are still
+/// read in parallel on devices without a concurrency limit.
+const UNIT_LENGTH: u64 = 64 << 20;
+
+/// How much of a source file is read at once.
+const CHUNK_LENGTH: usize = 1 << 20;
+
+/// Results of pieces checked by `verify_shared`, keyed by the address of each piece in the
+/// `Selection` it came from, so that looking one up is cheap. Results only apply to the selections
+/// the cache was built from.
+#[derive(Default)]
+pub struct PieceCache {
+    results: HashMap<usize, bool>,
+}
+
+impl PieceCache {
+    /// Returns whether `piece` matched, if it was checked as part of the shared pass.
+    pub fn get(&self, piece: &torrent::Piece) -> Option<bool> {
+        self.results
+            .get(&(std::ptr::from_ref(piece) as usize))
+            .copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+}
+
+/// The pieces one torrent in a batch is going to check.
+pub struct SharedCheck<'a, 'b> {
+    pub selection: &'a Selection<'b>,
+    pub mapping: &'a HashMap<&'b Path, &'b Path>,
+    pub checkpoint: Option<&'a Checkpoint>,
+}
+
+/// A piece that one of the torrents is going to check.
+struct Target<'a> {
+    check: usize,
+    /// The position of the piece in its torrent's selection.
+    index: usize,
+    piece: &'a torrent::Piece,
+    length: u64,
+}
+
+/// The part of a target piece that comes from a single source file.
+#[derive(Clone, Copy)]
+struct Span {
+    start: u64,
+    end: u64,
+    target: usize,
+    /// Where the span starts within the piece.
+    position: u64,
+}
+
+/// The spans read from one source file, sorted by start.
+struct FileSpans {
+    spans: Vec<Span>,
+    longest: u64,
+}
+
+impl FileSpans {
+    fn overlapping(&self, start: u64, end: u64) -> impl Iterator<Item = &Span> {
+        // No span starting before this can reach `start`.
+        let first = self
+            .spans
+            .partition_point(|span| span.start < start.saturating_sub(self.longest));
+        self.spans[first..]
+            .iter()
+            .take_while(move |span| span.start < end)
+            .filter(move |span| span.end > start)
+    }
+}
+
+/// A piece being hashed from data that may arrive out of order, since its spans can be read by
+/// different tasks.
+struct Hasher {
+    sha1: Option<Sha1>,
+    hashed: u64,
+    length: u64,
+    /// Data that arrived ahead of `hashed`, by position in the piece.
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Hasher {
+    fn new(length: u64) -> Hasher {
+        Hasher {
+            sha1: Some(Sha1::new()),
+            hashed: 0,
+            length,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Feeds `data` found at `position` in the piece, returning the digest once the whole piece
+    /// has been hashed.
+    fn feed(&mut self, position: u64, data: &[u8]) -> Option<[u8; sha1_smol::DIGEST_LENGTH]> {
+        let sha1 = self.sha1.as_mut()?;
+        if position != self.hashed {
+            self.pending.insert(position, data.to_vec());
+            return None;
+        }
+        sha1.update(data);
+        self.hashed += data.len() as u64;
+        while let Some(data) = self.pending.remove(&self.hashed) {
+            sha1.update(&data);
+            self.hashed += data.len() as u64;
+        }
+        if self.hashed < self.length {
+            return None;
+        }
+        self.sha1.take().map(Sha1::digest)
+    }
+}
+
+/// A contiguous range of a source file read by a single task.
+struct Unit<'a> {
+    source: &'a Path,
+    start: u64,
+    end: u64,
+}
+
+/// Checks, in a single pass, every piece covering source data that another torrent in `checks` is
+/// also going to check, and returns their results. Each source range is read once and fed to every
+/// piece that covers it, so torrents for the same data share reads even if their piece lengths
+/// differ. Pieces needed by only one torrent, or that could not be read, are left for that
+/// torrent's own `verify`. For full checks, matching pieces are recorded in each torrent's
+/// checkpoint, so that an interrupted batch does not read them again.
+pub fn verify_shared(checks: &[SharedCheck], options: &VerifyOptions) -> PieceCache {
+    let mut targets = vec![];
+    let mut spans = HashMap::<&Path, Vec<Span>>::new();
+    for (check_index, check) in checks.iter().enumerate() {
+        'pieces: for (index, piece) in check.selection.pieces.iter().enumerate() {
+            let resumed = check.selection.full
+                && check
+                    .checkpoint
+                    .is_some_and(|checkpoint| checkpoint.is_verified(index));
+            if resumed {
+                continue;
+            }
+            let mut piece_spans = vec![];
+            let mut position = 0;
+            for slice in &piece.file_slices {
+                let Some(&source) = check.mapping.get::<Path>(slice.path.as_ref()) else {
+                    continue 'pieces;
+                };
+                if slice.length > 0 {
+                    piece_spans.push((
+                        source,
+                        Span {
+                            start: slice.offset,
+                            end: slice.offset + slice.length,
+                            target: targets.len(),
+                            position,
+                        },
+                    ));
+                }
+                position += slice.length;
+            }
+            if position == 0 {
+                continue;
+            }
+            targets.push(Target {
+                check: check_index,
+                index,
+                piece,
+                length: position,
+            });
+            for (source, span) in piece_spans {
+                spans.entry(source).or_default().push(span);
+            }
+        }
+    }
+
+    // A piece is shared if any of its spans overlaps one from another torrent.
+    let mut shared = vec![false; targets.len()];
+    for spans in spans.values_mut() {
+        spans.sort_unstable_by_key(|span| span.start);
+        // The span reaching furthest so far from each torrent.
+        let mut furthest = HashMap::<usize, Span>::new();
+        for span in spans.iter() {
+            let check = targets[span.target].check;
+            for (&other, reached) in &furthest {
+                if other != check && reached.end > span.start {
+                    shared[span.target] = true;
+                    shared[reached.target] = true;
+                }
+            }
+            let reached = furthest.entry(check).or_insert(*span);
+            if span.end > reached.end {
+                *reached = *span;
+            }
+        }
+    }
+    let files: HashMap<&Path, FileSpans> = spans
+        .into_iter()
+        .filter_map(|(source, mut spans)| {
+            spans.retain(|span| shared[span.target]);
+            let longest = spans.iter().map(|span| span.end - span.start).max()?;
+            Some((source, FileSpans { spans, longest }))
+        })
+        .collect();
+    if files.is_empty() {
+        return PieceCache::default();
+    }
+
+    // Merge overlapping spans into the ranges to read, split into units so that large files are
+    // read in parallel. Units are grouped by device and laid out into lanes like pieces are.
+    let mut groups = HashMap::<Option<u64>, Vec<Unit>>::new();
+    let mut sources: Vec<_> = files.keys().copied().collect();
+    sources.sort_unstable();
+    for source in sources {
+        let units = groups.entry(device_of(source)).or_default();
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        for span in &files[source].spans {
+            match ranges.last_mut() {
+                Some((_, end)) if span.start <= *end => *end = (*end).max(span.end),
+                _ => ranges.push((span.start, span.end)),
+            }
+        }
+        for (start, end) in ranges {
+            units.extend(
+                (start..end)
+                    .step_by(UNIT_LENGTH as usize)
+                    .map(|offset| Unit {
+                        source,
+                        start: offset,
+                        end: (offset + UNIT_LENGTH).min(end),
+                    }),
+            );
+        }
+    }
+    let lanes: Vec<_> = groups
+        .into_iter()
+        .flat_map(|(dev, units)| {
+            let lanes = match dev.and_then(|dev| options.concurrency.limit(dev)) {
+                Some(limit) => limit,
+                None => units.len(),
+            };
+            split_lanes(units, lanes)
+                .into_iter()
+                .map(move |lane| (dev, lane))
+        })
+        .collect();
+
+    let hashers: Vec<_> = targets
+        .iter()
+        .zip(&shared)
+        .map(|(target, shared)| shared.then(|| Mutex::new(Hasher::new(target.length))))
+        .collect();
+    let shared_count = shared.iter().filter(|shared| **shared).count();
+    let bar =
+        util::new_bar(options.output, shared_count as u64).with_message("hashing shared pieces...");
+    let results = Mutex::new(HashMap::new());
+    with_limiter(options, |limiter| {
+        lanes.into_par_iter().for_each(|(dev, lane)| {
+            options.io_priority.apply();
+            // Held for the whole lane, so that its reads stay sequential.
+            let _slot = dev.map(|dev| limiter.slots.acquire(dev));
+            let mut buffer = vec![0; CHUNK_LENGTH];
+            for unit in lane {
+                let spans = &files[unit.source];
+                read_unit(&unit, options, &mut buffer, |offset, data| {
+                    limiter.throttle.consume(dev, data.len() as u64);
+                    let end = offset + data.len() as u64;
+                    for span in spans.overlapping(offset, end) {
+                        let (start, stop) = (span.start.max(offset), span.end.min(end));
+                        let position = span.position + (start - span.start);
+                        let data = &data[(start - offset) as usize..(stop - offset) as usize];
+                        let hasher = hashers[span.target].as_ref().unwrap();
+                        let Some(digest) = hasher.lock().unwrap().feed(position, data) else {
+                            continue;
+                        };
+                        let target = &targets[span.target];
+                        let piece = target.piece;
+                        let matches = digest == piece.hash.bytes();
+                        let check = &checks[target.check];
+                        let checkpoint = check.checkpoint.filter(|_| check.selection.full);
+                        if let Some(checkpoint) = checkpoint.filter(|_| matches) {
+                            if let Err(err) = checkpoint.mark_verified(target.index) {
+                                crate::status!(options.output, "unable to save checkpoint: {err}");
+                            }
+                        }
+                        results
+                            .lock()
+                            .unwrap()
+                            .insert(std::ptr::from_ref(piece) as usize, matches);
+                        bar.inc(1);
+                    }
+                });
+            }
+        });
+    });
+    for check in checks.iter().filter(|check| check.selection.full) {
+        if let Some(Err(err)) = check.checkpoint.map(Checkpoint::finish) {
+            crate::status!(options.output, "unable to save checkpoint: {err}");
+        }
+    }
+    PieceCache {
+        results: results.into_inner().unwrap(),
+    }
+}
+
+/// Reads `unit` in chunks, passing each one to `on_chunk` along with its offset in the file. Stops
+/// at the first error or the end of the file, leaving the pieces that were not fully read to be
+/// checked, and their errors reported, by each torrent's own `verify`.
+fn read_unit(
+    unit: &Unit,
+    options: &VerifyOptions,
+    buffer: &mut [u8],
+    mut on_chunk: impl FnMut(u64, &[u8]),
+) {
+    let mut file: Option<File> = None;
+    let mut offset = unit.start;
+    while offset < unit.end {
+        let length = buffer.len().min((unit.end - offset) as usize);
+        let buffer = &mut buffer[..length];
+        let result = options.retry.run(|| {
+            if file.is_none() {
+                file = Some(File::open(unit.source)?);
+            }
+            let result = rustix::io::pread(file.as_ref().unwrap(), &mut *buffer, offset);
+            if result.is_err() {
+                // Reopen on retry, in case the error is tied to the open file.
+                file = None;
+            }
+            Ok(result?)
+        });
+        match result {
+            Ok(0) | Err(_) => return,
+            Ok(bytes_read) => {
+                on_chunk(offset, &buffer[..bytes_read]);
+                offset += bytes_read as u64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::testing::hashed_piece;
+    use std::borrow::Cow;
+
+    #[test]
+    fn cache_keyed_by_piece() {
+        let piece = torrent::Piece {
+            hash: torrent::Digest::default(),
+            file_slices: vec![],
+        };
+        let identical = piece.clone();
+        let cache = PieceCache {
+            results: HashMap::from([(std::ptr::from_ref(&piece) as usize, true)]),
+        };
+        assert_eq!(cache.get(&piece), Some(true));
+        assert_eq!(cache.get(&identical), None);
+    }
+
+    /// Splits `data` stored at `path` into pieces of `piece_length`, with `corrupt` hashed wrong.
+    fn pieces(data: &[u8], path: &str, piece_length: usize, corrupt: usize) -> Vec<torrent::Piece> {
+        data.chunks(piece_length)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let offset = (index * piece_length) as u64;
+                let contents = if index == corrupt { b"wrong" } else { chunk };
+                hashed_piece(contents, &[(path, offset, chunk.len() as u64)])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn shares_reads_between_piece_lengths() {
+        let dir = util::TempDir::new("shared");
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        std::fs::write(dir.join("release.mkv"), &data).unwrap();
+        std::fs::write(dir.join("other"), &data).unwrap();
+        let source = dir.join("release.mkv");
+        let other = dir.join("other");
+
+        let selection = |pieces| Selection {
+            pieces: Cow::Owned(pieces),
+            full: true,
+            seed: None,
+        };
+        let first = selection(pieces(&data, "a/release.mkv", 4096, usize::MAX));
+        let second = selection(pieces(&data, "b.mkv", 3000, 2));
+        let third = selection(pieces(&data, "c", 4096, usize::MAX));
+        let first_mapping = HashMap::from([(Path::new("a/release.mkv"), source.as_path())]);
+        let second_mapping = HashMap::from([(Path::new("b.mkv"), source.as_path())]);
+        let third_mapping = HashMap::from([(Path::new("c"), other.as_path())]);
+        let second_info = torrent::Info {
+            files: vec![],
+            is_single_file: true,
+            name: "b.mkv".into(),
+            piece_length: 3000,
+            pieces: second.pieces.to_vec(),
+            source: None,
+            private: false,
+        };
+        let checkpoints = dir.join("checkpoints");
+        let checkpoint = Checkpoint::load(
+            &checkpoints,
+            &torrent::Digest::default(),
+            &second_info,
+            &second_mapping,
+        )
+        .unwrap();
+        let checks = [
+            SharedCheck {
+                selection: &first,
+                mapping: &first_mapping,
+                checkpoint: None,
+            },
+            SharedCheck {
+                selection: &second,
+                mapping: &second_mapping,
+                checkpoint: Some(&checkpoint),
+            },
+            SharedCheck {
+                selection: &third,
+                mapping: &third_mapping,
+                checkpoint: None,
+            },
+        ];
+
+        let cache = verify_shared(&checks, &VerifyOptions::default());
+        let results = |selection: &Selection| -> Vec<_> {
+            selection
+                .pieces
+                .iter()
+                .map(|piece| cache.get(piece))
+                .collect()
+        };
+        assert_eq!(results(&first), vec![Some(true); 3]);
+        assert_eq!(
+            results(&second),
+            vec![Some(true), Some(true), Some(false), Some(true)]
+        );
+        // Only one torrent reads the other file, so it is left for that torrent's own check.
+        assert_eq!(results(&third), vec![None; 3]);
+
+        let verified: Vec<_> = (0..4).map(|index| checkpoint.is_verified(index)).collect();
+        assert_eq!(verified, vec![true, true, false, true]);
+        assert_eq!(std::fs::read_dir(&checkpoints).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,158 @@
+use super::device::device_of;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The I/O scheduling priority to give hashing threads.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoPriority {
+    /// Leave the priority unchanged.
+    #[default]
+    Normal,
+    /// The lowest best-effort priority, so other readers on the same disk are served first.
+    Low,
+    /// Only read when no other process has asked for disk time. Hashing may stall indefinitely
+    /// on a busy disk.
+    Idle,
+}
+
+impl IoPriority {
+    /// Applies the priority to the calling thread. Only supported on Linux; failures are ignored,
+    /// since the priority is only a hint.
+    pub fn apply(self) {
+        #[cfg(target_os = "linux")]
+        {
+            const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+            const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+            const IOPRIO_CLASS_BE: libc::c_int = 2;
+            const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+            let ioprio = match self {
+                IoPriority::Normal => return,
+                IoPriority::Low => IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT | 7,
+                IoPriority::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            };
+            // SAFETY: ioprio_set takes only integer arguments; a `who` of 0 means the calling
+            // thread.
+            unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+        }
+    }
+}
+
+/// Limits on how fast source files are read, in bytes per second.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimits {
+    /// Limit across all devices.
+    pub global: Option<u64>,
+    /// Limit for each device, unless overridden in `devices`.
+    pub per_device: Option<u64>,
+    /// Overrides keyed by a path on the device, e.g. a mount point.
+    pub devices: HashMap<PathBuf, u64>,
+}
+
+impl RateLimits {
+    /// Returns the rate limit for `dev`, or `None` if it is unlimited.
+    fn limit(&self, dev: u64) -> Option<u64> {
+        self.devices
+            .iter()
+            .find(|(path, _)| device_of(path) == Some(dev))
+            .map(|(_, rate)| *rate)
+            .or(self.per_device)
+    }
+}
+
+/// A limiter shared by every worker reading from the same device (or from any device, for the
+/// global limit). Reads are charged after they complete, and the reader then sleeps until the
+/// limiter has caught up, so the long-run rate stays within the limit while each worker may run
+/// at most one piece ahead of it.
+#[derive(Debug)]
+struct RateLimiter {
+    bytes_per_sec: u64,
+    /// When the bytes charged so far will have been paid for.
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Charges `bytes` to the limiter, returning how long the caller should wait at `now` before
+    /// reading more.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let mut next = self.next.lock().unwrap();
+        // Time spent idle is not banked, so a limiter that has not been used for a while does not
+        // allow an unbounded burst.
+        let start = std::cmp::max(*next, now);
+        *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+        next.saturating_duration_since(now)
+    }
+}
+
+/// The rate limiters for a set of verifications.
+#[derive(Debug)]
+pub struct Throttle {
+    global: Option<RateLimiter>,
+    devices: Mutex<HashMap<u64, Option<Arc<RateLimiter>>>>,
+    limits: RateLimits,
+}
+
+impl Throttle {
+    pub fn new(limits: &RateLimits) -> Throttle {
+        Throttle {
+            global: limits.global.map(RateLimiter::new),
+            devices: Mutex::new(HashMap::new()),
+            limits: limits.clone(),
+        }
+    }
+
+    /// Charges `bytes` read from `dev` against the limits, sleeping if they have been exceeded.
+    pub fn consume(&self, dev: Option<u64>, bytes: u64) {
+        let device = dev.and_then(|dev| {
+            self.devices
+                .lock()
+                .unwrap()
+                .entry(dev)
+                .or_insert_with(|| {
+                    self.limits
+                        .limit(dev)
+                        .map(|rate| Arc::new(RateLimiter::new(rate)))
+                })
+                .clone()
+        });
+        let now = Instant::now();
+        let delay = [self.global.as_ref(), device.as_deref()]
+            .into_iter()
+            .flatten()
+            .map(|limiter| limiter.reserve(bytes, now))
+            .max();
+        if let Some(delay) = delay.filter(|delay| !delay.is_zero()) {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_reserve() {
+        let limiter = RateLimiter::new(1000);
+        let start = *limiter.next.lock().unwrap();
+        assert_eq!(limiter.reserve(500, start), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(500, start), Duration::from_secs(1));
+        assert_eq!(
+            limiter.reserve(1000, start + Duration::from_millis(1500)),
+            Duration::from_secs(1)
+        );
+        // Idle time does not accumulate credit.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(250, later), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,300 @@
+use super::hash::Sha1;
+use super::reader::PieceReader;
+use super::RetryPolicy;
+use crate::error::Result;
+use crate::torrent;
+use io_uring::{opcode, types, IoUring};
+use rustix::io::Errno;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// How many read requests may be outstanding at once, per worker.
+const RING_ENTRIES: usize = 64;
+/// How many pieces may be in flight at once, per worker.
+const MAX_PIECES_IN_FLIGHT: usize = 8;
+/// How many source files each worker keeps open at once, when not needed by in-flight reads.
+const MAX_OPEN_FILES: usize = 16;
+
+thread_local! {
+    /// Each worker thread keeps one ring for every lane it checks, rather than setting one up per
+    /// lane. Cleared if the ring fails, so the next lane starts with a fresh one.
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+/// A piece whose slices have been submitted for reading.
+struct InFlight<'a> {
+    piece: &'a torrent::Piece,
+    buffer: Vec<u8>,
+    /// Slices that have not completed yet.
+    pending: usize,
+    /// False if any slice failed or returned fewer bytes than requested.
+    ok: bool,
+}
+
+/// Checks `lane` using io_uring, keeping reads for upcoming pieces in flight while completed
+/// pieces are hashed. Pieces that cannot be read this way (e.g. due to I/O errors or short reads)
+/// are rechecked with `PieceReader`, which handles retries and error reporting. Returns `false`
+/// without checking anything if io_uring is unavailable.
+pub fn check_lane<'a>(
+    lane: &[&'a torrent::Piece],
+    mapping: &HashMap<&Path, &Path>,
+    retry: &RetryPolicy,
+    on_result: &mut impl FnMut(&'a torrent::Piece, Result<bool>),
+) -> bool {
+    let ring = RING
+        .with_borrow_mut(Option::take)
+        .or_else(|| IoUring::new(RING_ENTRIES as u32).ok());
+    let Some(mut ring) = ring else {
+        return false;
+    };
+    let mut fallback = PieceReader::for_thread(mapping, retry);
+    if check_with_ring(&mut ring, lane, mapping, &mut fallback, on_result) {
+        RING.with_borrow_mut(|slot| *slot = Some(ring));
+    }
+    true
+}
+
+/// Checks `lane` through `ring`. Returns `false` if the ring failed and should not be reused, in
+/// which case the rest of the lane has been checked with `fallback`.
+fn check_with_ring<'a>(
+    ring: &mut IoUring,
+    lane: &[&'a torrent::Piece],
+    mapping: &HashMap<&Path, &Path>,
+    fallback: &mut PieceReader,
+    on_result: &mut impl FnMut(&'a torrent::Piece, Result<bool>),
+) -> bool {
+    let mut files = HashMap::<&Path, File>::new();
+    let mut buffers = Vec::<Vec<u8>>::new();
+    let mut in_flight = VecDeque::<InFlight>::new();
+    // Sequence number of the piece at the front of `in_flight`, used to route completions.
+    let mut front_seq = 0u64;
+    let mut in_flight_slices = 0;
+    let mut upcoming = lane.iter().copied().peekable();
+
+    loop {
+        while in_flight.len() < MAX_PIECES_IN_FLIGHT {
+            let Some(&piece) = upcoming.peek() else {
+                break;
+            };
+            let slice_count = piece.file_slices.len();
+            if slice_count > RING_ENTRIES {
+                upcoming.next();
+                on_result(piece, fallback.check(piece));
+                continue;
+            }
+            if in_flight_slices + slice_count > RING_ENTRIES {
+                break;
+            }
+            upcoming.next();
+            let seq = front_seq + in_flight.len() as u64;
+            let mut buffer = buffers.pop().unwrap_or_default();
+            match submit(ring, piece, seq, mapping, &mut files, &mut buffer) {
+                Some(()) => {
+                    in_flight.push_back(InFlight {
+                        piece,
+                        buffer,
+                        pending: slice_count,
+                        ok: true,
+                    });
+                    in_flight_slices += slice_count;
+                }
+                None => {
+                    buffers.push(buffer);
+                    on_result(piece, fallback.check(piece));
+                }
+            }
+        }
+        if in_flight.is_empty() {
+            break;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            // Interrupted, or the kernel is short of resources until completions are reaped, which
+            // happens below; either way, submitting again is safe.
+            Err(err)
+                if matches!(
+                    Errno::from_io_error(&err),
+                    Some(Errno::INTR | Errno::AGAIN | Errno::BUSY)
+                ) => {}
+            Err(_) => {
+                // The kernel may still write into the buffers of in-flight reads, or read from
+                // their files, so both are leaked rather than freed or reused.
+                for InFlight { piece, buffer, .. } in in_flight {
+                    std::mem::forget(buffer);
+                    on_result(piece, fallback.check(piece));
+                }
+                std::mem::forget(files);
+                for piece in upcoming {
+                    on_result(piece, fallback.check(piece));
+                }
+                return false;
+            }
+        }
+        let completions: Vec<_> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completions {
+            let (seq, slice_index) = (user_data >> 32, (user_data & 0xffff_ffff) as usize);
+            let entry = &mut in_flight[(seq - front_seq) as usize];
+            let expected = entry.piece.file_slices[slice_index].length;
+            if result < 0 || result as u64 != expected {
+                entry.ok = false;
+            }
+            entry.pending -= 1;
+            in_flight_slices -= 1;
+        }
+        while in_flight.front().is_some_and(|entry| entry.pending == 0) {
+            let entry = in_flight.pop_front().unwrap();
+            front_seq += 1;
+            let result = if entry.ok {
+                let mut sha1 = Sha1::new();
+                sha1.update(&entry.buffer);
+                Ok(sha1.digest() == entry.piece.hash.bytes())
+            } else {
+                fallback.check(entry.piece)
+            };
+            on_result(entry.piece, result);
+            buffers.push(entry.buffer);
+        }
+        if files.len() > MAX_OPEN_FILES {
+            let in_use: HashSet<_> = in_flight
+                .iter()
+                .flat_map(|entry| &entry.piece.file_slices)
+                .filter_map(|slice| mapping.get::<Path>(slice.path.as_ref()))
+                .collect();
+            files.retain(|path, _| in_use.contains(path));
+        }
+    }
+    true
+}
+
+/// Queues reads for every slice of `piece` into `buffer`. Returns `None` if any source file
+/// cannot be opened, in which case nothing is queued.
+fn submit<'a>(
+    ring: &mut IoUring,
+    piece: &torrent::Piece,
+    seq: u64,
+    mapping: &HashMap<&'a Path, &'a Path>,
+    files: &mut HashMap<&'a Path, File>,
+    buffer: &mut Vec<u8>,
+) -> Option<()> {
+    let mut entries = Vec::with_capacity(piece.file_slices.len());
+    let total: u64 = piece.file_slices.iter().map(|slice| slice.length).sum();
+    buffer.resize(usize::try_from(total).ok()?, 0);
+    let mut offset = 0;
+    for (index, slice) in piece.file_slices.iter().enumerate() {
+        let path = *mapping.get::<Path>(slice.path.as_ref())?;
+        let fd = match files.entry(path) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.get().as_raw_fd(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(File::open(path).ok()?).as_raw_fd()
+            }
+        };
+        let length = usize::try_from(slice.length).ok()?;
+        let target = &mut buffer[offset..offset + length];
+        entries.push(
+            opcode::Read::new(
+                types::Fd(fd),
+                target.as_mut_ptr(),
+                u32::try_from(length).ok()?,
+            )
+            .offset(slice.offset)
+            .build()
+            .user_data(seq << 32 | index as u64),
+        );
+        offset += length;
+    }
+    let mut submission = ring.submission();
+    for entry in &entries {
+        // SAFETY: the buffer and file descriptors outlive the request: in-flight pieces own their
+        // buffers and their files are not closed until all of their reads have completed.
+        unsafe { submission.push(entry) }.expect("submission queue sized for in-flight slices");
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify::testing::hashed_piece;
+
+    #[test]
+    fn matches_piece_reader() {
+        const PIECE: usize = 4096;
+        let dir = crate::util::TempDir::new("uring");
+        let data: Vec<u8> = (0..20 * PIECE as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        // The data is split across two files at a point that is not a piece boundary.
+        let split = 7 * PIECE + 100;
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std::fs::write(&a, &data[..split]).unwrap();
+        std::fs::write(&b, &data[split..]).unwrap();
+        let missing = dir.join("missing");
+        let mapping = HashMap::from([
+            (Path::new("a"), a.as_path()),
+            (Path::new("b"), b.as_path()),
+            (Path::new("c"), missing.as_path()),
+        ]);
+
+        // The slices covering the piece at `index`.
+        let slices = |index: usize| -> Vec<_> {
+            let (start, end) = (index * PIECE, (index + 1) * PIECE);
+            [("a", 0, split), ("b", split, data.len())]
+                .into_iter()
+                .filter(|&(_, file_start, file_end)| start < file_end && end > file_start)
+                .map(|(path, file_start, file_end)| {
+                    let offset = start.max(file_start);
+                    let length = end.min(file_end) - offset;
+                    (path, (offset - file_start) as u64, length as u64)
+                })
+                .collect()
+        };
+        let mut pieces: Vec<_> = data
+            .chunks(PIECE)
+            .enumerate()
+            .map(|(index, chunk)| hashed_piece(chunk, &slices(index)))
+            .collect();
+        // A corrupted piece, one in a missing file, and one past the end of its file.
+        pieces[3] = hashed_piece(b"corrupted", &slices(3));
+        pieces.push(hashed_piece(&data[..PIECE], &[("c", 0, PIECE as u64)]));
+        pieces.push(hashed_piece(
+            &data[..PIECE],
+            &[("a", split as u64 - 10, PIECE as u64)],
+        ));
+        let lane: Vec<_> = pieces.iter().collect();
+        let retry = RetryPolicy {
+            retries: 0,
+            ..RetryPolicy::default()
+        };
+
+        let mut results = HashMap::new();
+        let checked = check_lane(&lane, &mapping, &retry, &mut |piece, result| {
+            results.insert(
+                std::ptr::from_ref(piece) as usize,
+                result.map_err(|err| err.to_string()),
+            );
+        });
+        if !checked {
+            eprintln!("io_uring is unavailable; skipping");
+            return;
+        }
+        assert_eq!(results.len(), pieces.len());
+        let mut reader = PieceReader::new(&mapping, &retry);
+        for (index, piece) in pieces.iter().enumerate() {
+            let expected = reader.check(piece).map_err(|err| err.to_string());
+            assert_eq!(
+                results[&(std::ptr::from_ref(piece) as usize)],
+                expected,
+                "piece {index}"
+            );
+        }
+        let matched = results.values().filter(|result| **result == Ok(true));
+        assert_eq!(matched.count(), 19);
+    }
+}